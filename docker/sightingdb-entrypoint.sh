@@ -2,7 +2,7 @@
 
 if [ -z $SIGHTINGDB_APIKEY ]
 then
-    echo "The environment variable SIGHTINGDB_API is not set, so we leave the default to 'changeme'."
+    echo "The environment variable SIGHTINGDB_API is not set, so an admin key is generated on the first start and printed below."
     /opt/sightingdb/target/release/sightingdb
 else
     /opt/sightingdb/target/release/sightingdb -k $SIGHTINGDB_APIKEY    
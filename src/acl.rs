@@ -1,13 +1,236 @@
-use crate::db::Database;
+use serde::{Deserialize, Serialize};
 
-pub fn can_read(db: &mut Database, authkey: &str, _namespace: &str) -> bool {
-    let mut apikey_namespace = String::from("_config/acl/apikeys/");
-    apikey_namespace.push_str(authkey);
-    db.namespace_exists(&apikey_namespace)
+use crate::ratelimit::Limits;
+use std::convert::TryFrom;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Permission {
+    Read,
+    Write,
+    Delete,
+}
+
+// "allow rw feeds/" or "deny d *": effect, permissions among r, w and d, then the
+// namespace prefix the rule applies to, '*' for every namespace.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Rule {
+    pub allow: bool,
+    pub read: bool,
+    pub write: bool,
+    pub delete: bool,
+    pub prefix: String,
+}
+
+impl Rule {
+    pub fn allow_all() -> Rule {
+        Rule {
+            allow: true,
+            read: true,
+            write: true,
+            delete: true,
+            prefix: String::new(),
+        }
+    }
+
+    pub(crate) fn grants(&self, permission: Permission) -> bool {
+        match permission {
+            Permission::Read => self.read,
+            Permission::Write => self.write,
+            Permission::Delete => self.delete,
+        }
+    }
+
+    pub(crate) fn applies(&self, permission: Permission, namespace: &str) -> bool {
+        self.grants(permission) && namespace.starts_with(self.prefix.as_str())
+    }
+}
+
+impl TryFrom<&str> for Rule {
+    type Error = String;
+
+    fn try_from(rule: &str) -> Result<Self, Self::Error> {
+        let error = || format!("Invalid ACL rule '{}', expected e.g. 'allow rw my/namespace/'", rule);
+        let parts: Vec<&str> = rule.split_whitespace().collect();
+        if parts.len() != 3 {
+            return Err(error());
+        }
+        let allow = match parts[0] {
+            "allow" => true,
+            "deny" => false,
+            _ => return Err(error()),
+        };
+        if parts[1].is_empty() || parts[1].chars().any(|c| !"rwd".contains(c)) {
+            return Err(error());
+        }
+        Ok(Rule {
+            allow,
+            read: parts[1].contains('r'),
+            write: parts[1].contains('w'),
+            delete: parts[1].contains('d'),
+            prefix: match parts[2] {
+                "*" => String::new(),
+                prefix => prefix.to_string(),
+            },
+        })
+    }
+}
+
+impl TryFrom<String> for Rule {
+    type Error = String;
+
+    fn try_from(rule: String) -> Result<Self, Self::Error> {
+        Rule::try_from(rule.as_str())
+    }
+}
+
+impl From<Rule> for String {
+    fn from(rule: Rule) -> String {
+        rule.to_string()
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut permissions = String::new();
+        for (granted, letter) in &[(self.read, 'r'), (self.write, 'w'), (self.delete, 'd')] {
+            if *granted {
+                permissions.push(*letter);
+            }
+        }
+        let prefix = if self.prefix.is_empty() { "*" } else { &self.prefix };
+        write!(f, "{} {} {}", if self.allow { "allow" } else { "deny" }, permissions, prefix)
+    }
+}
+
+pub fn parse_rules(rules: &str) -> Result<Vec<Rule>, String> {
+    rules
+        .split(',')
+        .map(str::trim)
+        .filter(|r| !r.is_empty())
+        .map(Rule::try_from)
+        .collect()
+}
+
+// Denials win over allows, and nothing is allowed unless a rule says so
+pub fn rules_allow(rules: &[Rule], permission: Permission, namespace: &str) -> bool {
+    let mut allowed = false;
+    for rule in rules.iter().filter(|r| r.applies(permission, namespace)) {
+        if !rule.allow {
+            return false;
+        }
+        allowed = true;
+    }
+    allowed
+}
+
+// For requests spanning every namespace: needs an allow on '*' and no deny at all
+pub fn rules_allow_all(rules: &[Rule], permission: Permission) -> bool {
+    rules_allow(rules, permission, "") && !rules.iter().any(|r| !r.allow && r.grants(permission))
+}
+
+// Who a request is made on behalf of, resolved once per request by the auth middleware
+#[derive(Clone, Debug, PartialEq)]
+pub struct Principal {
+    // Key id, or anonymous
+    pub id: String,
+    pub rules: Vec<Rule>,
+    // Can manage keys and access internal namespaces
+    pub admin: bool,
+    pub limits: Limits,
+}
+
+impl Principal {
+    // Requests without a key when authenticate=false
+    pub fn anonymous() -> Principal {
+        Principal {
+            id: String::from("anonymous"),
+            rules: vec![Rule::allow_all()],
+            admin: false,
+            limits: Limits::default(),
+        }
+    }
+
+    pub fn allows(&self, permission: Permission, namespace: &str) -> bool {
+        rules_allow(&self.rules, permission, namespace)
+    }
+
+    pub fn allows_all(&self, permission: Permission) -> bool {
+        rules_allow_all(&self.rules, permission)
+    }
+}
+
+// Namespaces the database keeps for itself
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reserved {
+    // _config and _internal: admins only
+    Internal,
+    // _all and _shadow: readable like any namespace, only the database writes them, admins delete them
+    Derived,
+}
+
+fn under(namespace: &str, root: &str) -> bool {
+    namespace
+        .strip_prefix(root)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+pub fn reserved(namespace: &str) -> Option<Reserved> {
+    if under(namespace, "_config") || under(namespace, "_internal") {
+        Some(Reserved::Internal)
+    } else if under(namespace, "_all") || under(namespace, "_shadow") {
+        Some(Reserved::Derived)
+    } else {
+        None
+    }
 }
 
-pub fn can_write(db: &mut Database, authkey: &str, _namespace: &str) -> bool {
-    let mut apikey_namespace = String::from("_config/acl/apikeys/");
-    apikey_namespace.push_str(authkey);
-    db.namespace_exists(&apikey_namespace)
+// Checked on every request, authenticate=false included
+pub fn reserved_allows(admin: bool, permission: Permission, namespace: &str) -> bool {
+    match reserved(namespace) {
+        None => true,
+        Some(Reserved::Internal) => admin,
+        Some(Reserved::Derived) => match permission {
+            Permission::Read => true,
+            Permission::Write => false,
+            Permission::Delete => admin,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deny_wins() {
+        let principal = Principal {
+            id: String::from("test"),
+            rules: parse_rules("allow rw feeds/, deny w feeds/private/, allow d *").unwrap(),
+            admin: false,
+            limits: Limits::default(),
+        };
+        assert!(principal.allows(Permission::Write, "feeds/public"));
+        assert!(!principal.allows(Permission::Write, "feeds/private/x"));
+        assert!(principal.allows(Permission::Read, "feeds/private/x"));
+        assert!(!principal.allows(Permission::Read, "other"));
+        assert!(principal.allows(Permission::Delete, "other"));
+        assert!(!principal.allows_all(Permission::Read));
+        assert!(principal.allows_all(Permission::Delete));
+
+        assert_eq!(principal.rules[1].to_string(), "deny w feeds/private/");
+        assert!(parse_rules("allow x *").is_err());
+    }
+
+    #[test]
+    fn test_reserved() {
+        assert_eq!(reserved("_config/x"), Some(Reserved::Internal));
+        assert_eq!(reserved("_internal"), Some(Reserved::Internal));
+        assert_eq!(reserved("_all"), Some(Reserved::Derived));
+        assert_eq!(reserved("_shadow/feeds/a"), Some(Reserved::Derived));
+        assert_eq!(reserved("_allowed"), None);
+        assert_eq!(reserved("_tests/namespace"), None);
+        assert_eq!(reserved("feeds/_all"), None);
+    }
 }
@@ -0,0 +1,89 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::attribute::stats_bucket;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpikeSettings {
+    // Number of stats buckets before the current one making the baseline
+    pub baseline: i64,
+    // How many standard deviations above the baseline mean is a spike
+    pub threshold: f64,
+    // Below this count the current bucket is never a spike, whatever the baseline
+    pub min_count: u128,
+}
+
+impl Default for SpikeSettings {
+    fn default() -> Self {
+        SpikeSettings {
+            baseline: 24,
+            threshold: 3.0,
+            min_count: 10,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Spike {
+    pub spiking: bool,
+    // Start of the current bucket and its count so far
+    pub bucket: i64,
+    pub count: u128,
+    pub baseline_mean: f64,
+    pub baseline_stddev: f64,
+    // Distance from the baseline mean, in standard deviations (at least 1)
+    pub score: f64,
+}
+
+// Compares the bucket `now` falls in with the `baseline` buckets before it, empty ones counting as zero.
+pub fn detect(stats: &BTreeMap<i64, u128>, now: i64, granularity: i64, settings: &SpikeSettings) -> Spike {
+    let bucket = stats_bucket(now, granularity);
+    let count = stats.get(&bucket).copied().unwrap_or(0);
+
+    let baseline = settings.baseline.max(1);
+    let start = bucket - baseline * granularity;
+    let (sum, sum_squares) = stats
+        .range(start..bucket)
+        .fold((0f64, 0f64), |(sum, sum_squares), (_, c)| {
+            let c = *c as f64;
+            (sum + c, sum_squares + c * c)
+        });
+    let mean = sum / baseline as f64;
+    let variance = (sum_squares / baseline as f64 - mean * mean).max(0.0);
+    let stddev = variance.sqrt();
+    let score = (count as f64 - mean) / stddev.max(1.0);
+
+    Spike {
+        spiking: count >= settings.min_count && score >= settings.threshold,
+        bucket,
+        count,
+        baseline_mean: mean,
+        baseline_stddev: stddev,
+        score,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_spike() {
+        let settings = SpikeSettings {
+            baseline: 4,
+            threshold: 3.0,
+            min_count: 5,
+        };
+        let mut stats: BTreeMap<i64, u128> = (0..4).map(|h| (h * 3600, 2)).collect();
+        let now = 4 * 3600 + 10;
+
+        stats.insert(4 * 3600, 3);
+        assert!(!detect(&stats, now, 3600, &settings).spiking);
+
+        stats.insert(4 * 3600, 20);
+        let spike = detect(&stats, now, 3600, &settings);
+        assert!(spike.spiking);
+        assert_eq!(spike.baseline_mean, 2.0);
+        assert_eq!(spike.score, 18.0);
+    }
+}
@@ -0,0 +1,343 @@
+use atomicwrites::{AllowOverwrite, AtomicFile};
+use chrono::Utc;
+use ini::ini::Properties;
+use openssl::memcmp;
+use openssl::rand::rand_bytes;
+use openssl::sha::sha256;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::acl::{parse_rules, Permission, Principal, Rule};
+use crate::ratelimit::Limits;
+
+// Usage is persisted at most this often, management operations are saved right away
+const SAVE_USAGE_EVERY: i64 = 60;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RequestCounts {
+    pub read: u64,
+    pub write: u64,
+    pub delete: u64,
+}
+
+// What we know about a key, minus its secret, so it can be listed as is
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    #[serde(default)]
+    pub description: String,
+    pub rules: Vec<Rule>,
+    // Can manage keys
+    #[serde(default)]
+    pub admin: bool,
+    pub created: i64,
+    #[serde(default)]
+    pub last_used: i64,
+    #[serde(default)]
+    pub requests: RequestCounts,
+    #[serde(default)]
+    pub limits: Limits,
+}
+
+impl ApiKey {
+    pub fn new(id: &str, description: &str, rules: Vec<Rule>, admin: bool) -> ApiKey {
+        ApiKey {
+            id: id.to_string(),
+            description: description.to_string(),
+            rules,
+            admin,
+            created: Utc::now().timestamp(),
+            last_used: 0,
+            requests: RequestCounts::default(),
+            limits: Limits::default(),
+        }
+    }
+
+    pub fn full_access(id: &str, description: &str) -> ApiKey {
+        ApiKey::new(id, description, vec![Rule::allow_all()], true)
+    }
+
+    pub fn principal(&self) -> Principal {
+        Principal {
+            id: self.id.clone(),
+            rules: self.rules.clone(),
+            admin: self.admin,
+            limits: self.limits.clone(),
+        }
+    }
+}
+
+// Secrets are never kept, only a salted SHA-256 of them. Not flattened: serde_json
+// cannot read numbers back from flattened structs with arbitrary_precision.
+#[derive(Serialize, Deserialize)]
+struct StoredKey {
+    salt: String,
+    hash: String,
+    apikey: ApiKey,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hash_key(salt: &str, authkey: &str) -> String {
+    to_hex(&sha256(format!("{}{}", salt, authkey).as_bytes()))
+}
+
+impl StoredKey {
+    fn new(authkey: &str, apikey: ApiKey) -> StoredKey {
+        let mut key = StoredKey {
+            salt: String::new(),
+            hash: String::new(),
+            apikey,
+        };
+        key.set_secret(authkey);
+        key
+    }
+
+    fn set_secret(&mut self, authkey: &str) {
+        self.salt = random_string(16);
+        self.hash = hash_key(&self.salt, authkey);
+    }
+
+    // Constant time, so the comparison does not tell how close a guess was
+    fn matches(&self, authkey: &str) -> bool {
+        let hash = hash_key(&self.salt, authkey);
+        hash.len() == self.hash.len() && memcmp::eq(hash.as_bytes(), self.hash.as_bytes())
+    }
+}
+
+fn random_string(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    rand_bytes(&mut bytes).unwrap();
+    base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn random_id() -> String {
+    let mut bytes = [0u8; 6];
+    rand_bytes(&mut bytes).unwrap();
+    to_hex(&bytes)
+}
+
+// Keys set from the configuration file get a random id with this prefix, kept while they stay there
+const CONFIG_ID_PREFIX: &str = "config-";
+
+#[derive(Default)]
+pub struct KeyStore {
+    // By key id
+    keys: HashMap<String, StoredKey>,
+    // Where keys are persisted, nowhere when None
+    path: Option<PathBuf>,
+    last_saved: i64,
+}
+
+impl KeyStore {
+    pub fn new() -> KeyStore {
+        KeyStore::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    // Keys are loaded from, then saved to, apikeys.json in the database directory
+    pub fn open(&mut self, db_path: &str) -> Result<(), String> {
+        fs::create_dir_all(db_path).map_err(|e| format!("Cannot create {}: {}", db_path, e))?;
+        // Absolute, the daemon changes its working directory
+        let mut path = fs::canonicalize(db_path).map_err(|e| format!("Cannot open {}: {}", db_path, e))?;
+        path.push("apikeys.json");
+        if path.exists() {
+            let content = fs::read_to_string(&path)
+                .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+            let stored: Vec<StoredKey> = serde_json::from_str(&content)
+                .map_err(|e| format!("Cannot parse {}: {}", path.display(), e))?;
+            for key in stored {
+                self.keys.insert(key.apikey.id.clone(), key);
+            }
+        }
+        self.path = Some(path);
+        Ok(())
+    }
+
+    fn save(&mut self) {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        let mut stored: Vec<&StoredKey> = self.keys.values().collect();
+        stored.sort_by(|a, b| a.apikey.id.cmp(&b.apikey.id));
+        let content = serde_json::to_string_pretty(&stored).unwrap();
+        let written = AtomicFile::new(&path, AllowOverwrite).write(|f| f.write_all(content.as_bytes()));
+        match written {
+            Ok(_) => self.last_saved = Utc::now().timestamp(),
+            Err(e) => log::error!("Cannot save API keys to {}: {}", path.display(), e),
+        }
+    }
+
+    // Adds or replaces the key with the same id
+    pub fn insert(&mut self, authkey: &str, apikey: ApiKey) {
+        let key = StoredKey::new(authkey, apikey);
+        self.keys.insert(key.apikey.id.clone(), key);
+        self.save();
+    }
+
+    pub fn get(&self, authkey: &str) -> Option<&ApiKey> {
+        self.keys
+            .values()
+            .find(|stored| stored.matches(authkey))
+            .map(|stored| &stored.apikey)
+    }
+
+    pub fn record_use(&mut self, id: &str, permission: Permission) {
+        let now = Utc::now().timestamp();
+        let apikey = match self.keys.get_mut(id) {
+            Some(stored) => &mut stored.apikey,
+            None => return,
+        };
+        apikey.last_used = now;
+        match permission {
+            Permission::Read => apikey.requests.read += 1,
+            Permission::Write => apikey.requests.write += 1,
+            Permission::Delete => apikey.requests.delete += 1,
+        }
+        if now - self.last_saved >= SAVE_USAGE_EVERY {
+            self.save();
+        }
+    }
+
+    pub fn list(&self) -> Vec<&ApiKey> {
+        let mut keys: Vec<&ApiKey> = self.keys.values().map(|stored| &stored.apikey).collect();
+        keys.sort_by_key(|apikey| apikey.created);
+        keys
+    }
+
+    // First start: an admin key nobody could have guessed, to be shown once
+    pub fn create_admin(&mut self) -> String {
+        let authkey = random_string(24);
+        self.insert(&authkey, ApiKey::full_access("admin", "Generated on first start"));
+        authkey
+    }
+
+    // Returns the new key along with its secret, which is not given again afterwards
+    pub fn create(&mut self, description: &str, rules: Vec<Rule>, admin: bool, limits: Limits) -> (ApiKey, String) {
+        let apikey = ApiKey {
+            limits,
+            ..ApiKey::new(&random_id(), description, rules, admin)
+        };
+        let authkey = random_string(24);
+        self.insert(&authkey, apikey.clone());
+        (apikey, authkey)
+    }
+
+    // New secret for an existing key, the old one stops working
+    pub fn rotate(&mut self, id: &str) -> Option<(ApiKey, String)> {
+        let authkey = random_string(24);
+        let stored = self.keys.get_mut(id)?;
+        stored.set_secret(&authkey);
+        let apikey = stored.apikey.clone();
+        self.save();
+        Some((apikey, authkey))
+    }
+
+    pub fn revoke(&mut self, id: &str) -> bool {
+        let revoked = self.keys.remove(id).is_some();
+        if revoked {
+            self.save();
+        }
+        revoked
+    }
+
+    // [apikeys] section of the configuration: one key per line, with its rules separated by ','
+    // mykey = allow rw feeds/, deny w feeds/private/
+    // Keys that were in the configuration of a previous start but are not anymore are revoked.
+    pub fn load_config(&mut self, section: Option<&Properties>) -> Result<(), String> {
+        let mut configured = HashSet::new();
+        for (authkey, rules) in section.into_iter().flat_map(|section| section.iter()) {
+            let rules = parse_rules(rules).map_err(|e| format!("API key {}: {}", authkey, e))?;
+            // Keep the id and usage of a key we already know
+            let apikey = match self.get(authkey) {
+                Some(known) if known.id.starts_with(CONFIG_ID_PREFIX) => ApiKey {
+                    rules,
+                    ..known.clone()
+                },
+                Some(known) => return Err(format!("A key of the section is already the key {}", known.id)),
+                None => {
+                    let id = format!("{}{}", CONFIG_ID_PREFIX, random_id());
+                    ApiKey::new(&id, "From the configuration file", rules, false)
+                }
+            };
+            configured.insert(apikey.id.clone());
+            self.insert(authkey, apikey);
+        }
+
+        let before = self.keys.len();
+        self.keys
+            .retain(|id, _| !id.starts_with(CONFIG_ID_PREFIX) || configured.contains(id));
+        if self.keys.len() != before {
+            self.save();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotate_and_revoke() {
+        let mut keys = KeyStore::new();
+        let (apikey, authkey) = keys.create("writer", vec![Rule::allow_all()], false, Limits::default());
+        assert_eq!(keys.get(&authkey).unwrap().id, apikey.id);
+        assert!(keys.get("").is_none());
+        let stored = serde_json::to_string(&keys.keys[&apikey.id]).unwrap();
+        assert!(!stored.contains(&authkey));
+        let reloaded: StoredKey = serde_json::from_str(&stored).unwrap();
+        assert!(reloaded.matches(&authkey));
+
+        let (_, rotated) = keys.rotate(&apikey.id).unwrap();
+        assert!(keys.get(&authkey).is_none());
+        assert!(keys.get(&rotated).is_some());
+
+        assert!(keys.revoke(&apikey.id));
+        assert!(keys.get(&rotated).is_none());
+    }
+
+    #[test]
+    fn test_config_keys_follow_the_configuration() {
+        let mut ini = ini::Ini::new();
+        ini.with_section(Some("apikeys"))
+            .set("first", "allow r *")
+            .set("second", "allow w feeds/");
+        let mut keys = KeyStore::new();
+        let (created, _) = keys.create("writer", vec![Rule::allow_all()], false, Limits::default());
+        keys.load_config(ini.section(Some("apikeys"))).unwrap();
+        let first = keys.get("first").unwrap().clone();
+        assert!(first.id.starts_with(CONFIG_ID_PREFIX));
+        assert!(!keys.get("second").unwrap().rules.is_empty());
+        let stored = serde_json::to_string(&keys.keys[&first.id]).unwrap();
+        assert!(!stored.contains(&to_hex(&sha256(b"first"))[..8]));
+
+        // Removed from the configuration
+        ini.with_section(Some("apikeys")).delete("second");
+        keys.load_config(ini.section(Some("apikeys"))).unwrap();
+        assert_eq!(keys.get("first").unwrap().id, first.id);
+        assert!(keys.get("second").is_none());
+
+        // The secret of a key that is not from the configuration
+        let (_, authkey) = keys.create("reader", vec![Rule::allow_all()], false, Limits::default());
+        let mut duplicate = ini::Ini::new();
+        duplicate.with_section(Some("apikeys")).set(authkey.as_str(), "allow w *");
+        assert!(keys.load_config(duplicate.section(Some("apikeys"))).is_err());
+        assert!(keys.get(&authkey).unwrap().rules.iter().all(|rule| rule == &Rule::allow_all()));
+        keys.revoke(&keys.get(&authkey).unwrap().id.clone());
+
+        keys.load_config(None).unwrap();
+        assert!(keys.get("first").is_none());
+        assert_eq!(keys.list().len(), 1);
+        assert_eq!(keys.list()[0].id, created.id);
+    }
+}
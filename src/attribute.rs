@@ -7,6 +7,10 @@ use chrono::serde::ts_seconds;
 use std::collections::BTreeMap;
 use serde::ser::SerializeStruct;
 
+use crate::anomaly::{detect, Spike};
+use crate::series::{aggregate, Series, SeriesQuery};
+use crate::sighting_configure::{NamespaceSettings, RetentionRule};
+
 #[derive(Deserialize, Clone, PartialEq)]
 pub struct Attribute {
     pub value: String,
@@ -20,10 +24,46 @@ pub struct Attribute {
     pub stats: BTreeMap<i64, u128>,
     // i64 because DateTime.timestamp() returns i64 :'(; We track count by time.
     pub consensus: u128,
+    // Exponentially decayed count, as of score_time. See score_at() for its current value.
+    #[serde(default)]
+    pub score: f64,
+    #[serde(default)]
+    pub score_time: i64,
 }
 
 //"stats":{"1586548800":1},
 
+// Timestamp of writes without one: the sighting happens now. 0 is a real time, the epoch.
+pub const NOW: i64 = -1;
+
+// Key of the stats bucket a timestamp falls in
+pub fn stats_bucket(timestamp: i64, granularity: i64) -> i64 {
+    timestamp - timestamp.rem_euclid(granularity)
+}
+
+// Weight left after `elapsed` seconds
+fn decay(elapsed: i64, half_life: i64) -> f64 {
+    0.5f64.powf(elapsed as f64 / half_life as f64)
+}
+
+// Rolls the buckets older than a rule into the granularity of the next one, and drops
+// the ones older than the last rule. Only the histogram shrinks, counts are untouched.
+pub fn apply_retention(stats: &mut BTreeMap<i64, u128>, now: i64, rules: &[RetentionRule]) {
+    if rules.is_empty() {
+        return;
+    }
+    let mut rolled: BTreeMap<i64, u128> = BTreeMap::new();
+    for (&timestamp, &count) in stats.iter() {
+        let age = now - timestamp;
+        if let Some(rule) = rules.iter().find(|rule| age < rule.max_age) {
+            *rolled
+                .entry(stats_bucket(timestamp, rule.granularity))
+                .or_insert(0) += count;
+        }
+    }
+    *stats = rolled;
+}
+
 impl Attribute {
     pub fn new(value: &str) -> Attribute {
         Attribute {
@@ -35,45 +75,50 @@ impl Attribute {
             ttl: 0,
             stats: BTreeMap::new(),
             consensus: 0,
+            score: 0.0,
+            score_time: 0,
         }
     }
 
-    pub fn make_stats(&mut self, time: DateTime<Utc>) {
-        let rounded_time = time.timestamp() - time.timestamp() % 3600;
-        self.stats
-            .entry(rounded_time)
-            .and_modify(|e| *e += 1)
-            .or_insert(1);
+    // Returns true when a new bucket had to be created
+    pub fn make_stats(&mut self, time: DateTime<Utc>, granularity: i64) -> bool {
+        self.make_stats_from_timestamp(time.timestamp(), granularity)
     }
 
-    pub fn make_stats_from_timestamp(&mut self, timestamp: i64) {
-        let rounded_time = timestamp - timestamp % 3600;
+    pub fn make_stats_from_timestamp(&mut self, timestamp: i64, granularity: i64) -> bool {
+        let rounded_time = stats_bucket(timestamp, granularity);
+        let mut new_bucket = false;
         self.stats
             .entry(rounded_time)
             .and_modify(|e| *e += 1)
-            .or_insert(1);
+            .or_insert_with(|| {
+                new_bucket = true;
+                1
+            });
+        new_bucket
     }
 
     pub fn count(&mut self) -> u128 {
         self.count
     }
 
-    pub fn incr(&mut self) {
+    pub fn incr(&mut self, granularity: i64) -> bool {
         if self.first_seen.timestamp() == 0 {
             self.first_seen = Utc::now();
         }
         self.last_seen = Utc::now();
 
-        self.make_stats(self.last_seen);
+        let new_bucket = self.make_stats(self.last_seen, granularity);
 
         self.count += 1;
+        new_bucket
     }
 
     pub fn set_consensus(&mut self, consensus_count: u128) {
         self.consensus = consensus_count;
     }
 
-    pub fn incr_from_timestamp(&mut self, timestamp: i64) {
+    pub fn incr_from_timestamp(&mut self, timestamp: i64, granularity: i64) -> bool {
         if self.first_seen.timestamp() == 0 {
             self.first_seen =
                 DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(timestamp, 0), Utc);
@@ -86,22 +131,114 @@ impl Attribute {
             self.last_seen =
                 DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(timestamp, 0), Utc);
         }
-        self.make_stats_from_timestamp(timestamp);
+        let new_bucket = self.make_stats_from_timestamp(timestamp, granularity);
         self.count += 1;
+        new_bucket
     }
 
-    pub fn increment(&mut self, timestamp: i64) {
-        if timestamp.is_negative() {
-            self.incr()
+    // Each sighting weighs 1 when it happens, then half as much every half life
+    pub fn add_to_score(&mut self, timestamp: i64, half_life: i64) {
+        if self.score == 0.0 || timestamp >= self.score_time {
+            self.score = self.score_at(timestamp, half_life) + 1.0;
+            self.score_time = timestamp;
         } else {
-            self.incr_from_timestamp(timestamp)
+            // Sighting older than the score, it has already decayed by now
+            self.score += decay(self.score_time - timestamp, half_life);
+        }
+    }
+
+    pub fn score_at(&self, now: i64, half_life: i64) -> f64 {
+        if now <= self.score_time {
+            return self.score;
+        }
+        self.score * decay(now - self.score_time, half_life)
+    }
+
+    pub fn increment(&mut self, timestamp: i64, settings: &NamespaceSettings) {
+        let new_bucket = if timestamp.is_negative() {
+            self.incr(settings.stats_granularity)
+        } else {
+            self.incr_from_timestamp(timestamp, settings.stats_granularity)
+        };
+        let sighted_at = if timestamp.is_negative() {
+            self.last_seen.timestamp()
+        } else {
+            timestamp
+        };
+        self.add_to_score(sighted_at, settings.score_half_life);
+        // Rolling up once per new bucket is enough to keep the histogram bounded
+        if new_bucket {
+            apply_retention(&mut self.stats, Utc::now().timestamp(), &settings.stats_retention);
+        }
+    }
+
+    // Stats come along with other fields computed from them, like the spike
+    pub fn serialize_with_stats(&self, mut fields: Vec<(&str, serde_json::Value)>) -> Result<String> {
+        fields.push(("stats", serde_json::to_value(&self.stats)?));
+        self.serialize_with(fields)
+    }
+
+    // Stats bounded and aggregated server side, instead of the whole map
+    pub fn series(&self, query: &SeriesQuery) -> Series {
+        aggregate(&self.stats, query)
+    }
+
+    pub fn spike(&self, now: i64, settings: &NamespaceSettings) -> Spike {
+        detect(&self.stats, now, settings.stats_granularity, &settings.spike)
+    }
+
+    // Sightings over each configured window, ending with the current stats bucket.
+    // Windows are rounded up to whole buckets.
+    pub fn window_counts(&self, now: i64, settings: &NamespaceSettings) -> BTreeMap<String, u128> {
+        let granularity = settings.stats_granularity;
+        let current = stats_bucket(now, granularity);
+        let since = |window: i64| current - (window - 1).max(0) / granularity * granularity;
+        let oldest = settings
+            .count_windows
+            .iter()
+            .map(|(_, window)| since(*window))
+            .min()
+            .unwrap_or(current);
+
+        let mut counts: BTreeMap<String, u128> = settings
+            .count_windows
+            .iter()
+            .map(|(label, _)| (label.clone(), 0))
+            .collect();
+        // One walk over the largest window is enough for all of them
+        for (timestamp, count) in self.stats.range(oldest..) {
+            for (label, window) in &settings.count_windows {
+                if *timestamp >= since(*window) {
+                    *counts.get_mut(label).unwrap() += count;
+                }
+            }
+        }
+        counts
+    }
+
+    // Fields every read returns besides the attribute itself
+    pub fn read_fields(&self, now: i64, settings: &NamespaceSettings) -> Vec<(&'static str, serde_json::Value)> {
+        let mut fields = vec![(
+            "score",
+            serde_json::to_value(self.score_at(now, settings.score_half_life)).unwrap(),
+        )];
+        if !settings.count_windows.is_empty() {
+            fields.push(("windows", serde_json::to_value(self.window_counts(now, settings)).unwrap()));
         }
+        fields
     }
 
-    pub fn serialize_with_stats(&self) -> Result<String> {
-        let mut json_value = serde_json::to_value(&self)?;
-        json_value["stats"] = serde_json::to_value(&self.stats)?;
-        serde_json::to_string(&json_value).map_err(|e| e.into())
+    // The attribute along with fields computed at read time
+    pub fn to_value_with(&self, fields: Vec<(&str, serde_json::Value)>) -> Result<serde_json::Value> {
+        let mut json_value = serde_json::to_value(self)?;
+        for (name, value) in fields {
+            json_value[name] = value;
+        }
+        Ok(json_value)
+    }
+
+    pub fn serialize_with(&self, fields: Vec<(&str, serde_json::Value)>) -> Result<String> {
+        serde_json::to_string(&self.to_value_with(fields)?).map_err(|e| e.into())
     }
 }
 
@@ -143,9 +280,74 @@ mod tests {
         }
         let mut attr = Attribute::new("test");
         attr.stats = stats;
-        let serialized = &attr.serialize_with_stats()?;
-        let deserialized: Attribute = serde_json::from_str(&serialized)?;
+        let serialized = &attr.serialize_with_stats(Vec::new())?;
+        let deserialized: Attribute = serde_json::from_str(serialized)?;
         assert_eq!(deserialized, attr);
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_apply_retention() {
+        let rules = [
+            RetentionRule {
+                granularity: 3600,
+                max_age: 86400,
+            },
+            RetentionRule {
+                granularity: 86400,
+                max_age: 3 * 86400,
+            },
+        ];
+        let now = 10 * 86400;
+        let mut stats: BTreeMap<i64, u128> = BTreeMap::new();
+        stats.insert(now - 3600, 1);
+        stats.insert(now - 2 * 86400 + 3600, 2);
+        stats.insert(now - 2 * 86400 + 7200, 3);
+        stats.insert(now - 5 * 86400, 4);
+
+        apply_retention(&mut stats, now, &rules);
+        let expected: BTreeMap<i64, u128> = vec![(now - 2 * 86400, 5), (now - 3600, 1)].into_iter().collect();
+        assert_eq!(stats, expected);
+    }
+
+    #[test]
+    fn test_window_counts() {
+        let now = 100 * 86400 + 1800;
+        let mut attr = Attribute::new("test");
+        attr.stats.insert(now - 1800, 1);
+        attr.stats.insert(now - 1800 - 3600, 2);
+        attr.stats.insert(now - 1800 - 23 * 3600, 4);
+        attr.stats.insert(now - 1800 - 24 * 3600, 8);
+        attr.stats.insert(now - 1800 - 8 * 86400, 16);
+
+        let counts = attr.window_counts(now, &NamespaceSettings::default());
+        assert_eq!(counts["1h"], 1);
+        assert_eq!(counts["24h"], 7);
+        assert_eq!(counts["7d"], 15);
+    }
+
+    #[test]
+    fn test_score_decays() {
+        let half_life = 3600;
+        let mut attr = Attribute::new("test");
+        attr.add_to_score(1000, half_life);
+        attr.add_to_score(1000 + 3600, half_life);
+        assert_eq!(attr.score, 1.5);
+        assert_eq!(attr.score_at(1000 + 2 * 3600, half_life), 0.75);
+
+        // Late sightings are decayed to the score time
+        attr.add_to_score(1000, half_life);
+        assert_eq!(attr.score, 2.0);
+    }
+
+    #[test]
+    fn test_increment_now() {
+        let settings = NamespaceSettings::default();
+        let mut attr = Attribute::new("test");
+        attr.increment(NOW, &settings);
+        let now = Utc::now().timestamp();
+        assert!(attr.last_seen.timestamp() >= now - 1);
+        assert!(attr.score_at(now, settings.score_half_life) > 0.99);
+        assert_eq!(attr.window_counts(now, &settings)["1h"], 1);
+    }
+}
@@ -0,0 +1,245 @@
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::HttpRequest;
+use chrono::Utc;
+use ini::ini::Properties;
+use openssl::sha::sha256;
+use qstring::QString;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::acl::Principal;
+use crate::SharedState;
+
+// Who did what to which namespace, kept apart from db_log: every event is a JSON line sent to the
+// sightingdb::audit logger (a rolling file in etc/log4rs.yml), the most recent ones are also kept
+// in memory for /audit.
+//
+// [audit]
+// enabled=true
+// hash_values=false    <- log the SHA-256 of values rather than the values
+// memory=10000         <- events kept for /audit
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditEvent {
+    pub time: i64,
+    // Key id, jwt:<sub>, cert:<name> or anonymous, None when the request had no valid credentials
+    pub principal: Option<String>,
+    pub endpoint: String,
+    pub namespace: String,
+    pub value: Option<String>,
+    pub status: u16,
+    pub outcome: &'static str,
+    pub remote: Option<String>,
+}
+
+// Namespaces and values of a bulk request, left in the request extensions by the handler
+// so the audit has one event per item rather than one for the whole request
+#[derive(Clone, Debug, Default)]
+pub struct AuditItems(pub Vec<(String, String)>);
+
+pub fn outcome(status: u16) -> &'static str {
+    match status {
+        200..=299 => "ok",
+        401 | 403 => "denied",
+        _ => "error",
+    }
+}
+
+// "/w/my/namespace" is endpoint /w on namespace my/namespace
+pub fn split_path(path: &str) -> (String, String) {
+    let path = path.trim_start_matches('/');
+    let mut parts = path.splitn(2, '/');
+    let endpoint = format!("/{}", parts.next().unwrap_or(""));
+    (endpoint, parts.next().unwrap_or("").to_string())
+}
+
+// What is known before the request is handled, completed by finish once it is
+pub fn start(req: &ServiceRequest) -> AuditEvent {
+    let (endpoint, namespace) = split_path(req.path());
+    AuditEvent {
+        time: Utc::now().timestamp(),
+        principal: None,
+        endpoint: format!("{} {}", req.method(), endpoint),
+        namespace,
+        value: QString::from(req.query_string()).get("val").map(str::to_string),
+        status: 0,
+        outcome: "",
+        remote: req.peer_addr().map(|addr| addr.ip().to_string()),
+    }
+}
+
+pub fn finish<B>(state: &Arc<Mutex<SharedState>>, mut event: AuditEvent, res: &ServiceResponse<B>) {
+    let request = res.request();
+    event.principal = request
+        .extensions()
+        .get::<Principal>()
+        .map(|principal| principal.id.clone());
+    event.status = res.status().as_u16();
+    event.outcome = outcome(event.status);
+
+    let audit = &mut state.lock().unwrap().audit;
+    match request.extensions().get::<AuditItems>() {
+        Some(AuditItems(items)) if !items.is_empty() => {
+            for (namespace, value) in items {
+                audit.record(AuditEvent {
+                    namespace: namespace.clone(),
+                    value: Some(value.clone()),
+                    ..event.clone()
+                });
+            }
+        }
+        _ => audit.record(event),
+    }
+}
+
+// Streamed requests are answered before they are read: the handler records their items itself,
+// as they are applied
+pub fn item_event(req: &HttpRequest, namespace: &str, value: &str, status: u16) -> AuditEvent {
+    let (endpoint, _) = split_path(req.path());
+    AuditEvent {
+        time: Utc::now().timestamp(),
+        principal: req
+            .extensions()
+            .get::<Principal>()
+            .map(|principal| principal.id.clone()),
+        endpoint: format!("{} {}", req.method(), endpoint),
+        namespace: namespace.to_string(),
+        value: Some(value.to_string()),
+        status,
+        outcome: outcome(status),
+        remote: req.peer_addr().map(|addr| addr.ip().to_string()),
+    }
+}
+
+#[derive(Default)]
+pub struct AuditQuery {
+    pub principal: Option<String>,
+    // Namespace prefix
+    pub namespace: Option<String>,
+    pub outcome: Option<String>,
+    pub since: Option<i64>,
+}
+
+impl AuditQuery {
+    fn matches(&self, event: &AuditEvent) -> bool {
+        self.principal.as_ref().is_none_or(|p| event.principal.as_ref() == Some(p))
+            && self.namespace.as_ref().is_none_or(|ns| event.namespace.starts_with(ns.as_str()))
+            && self.outcome.as_ref().is_none_or(|o| event.outcome == o)
+            && self.since.is_none_or(|since| event.time >= since)
+    }
+}
+
+pub struct AuditLog {
+    enabled: bool,
+    hash_values: bool,
+    capacity: usize,
+    recent: VecDeque<AuditEvent>,
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        AuditLog {
+            enabled: true,
+            hash_values: false,
+            capacity: 10_000,
+            recent: VecDeque::new(),
+        }
+    }
+}
+
+impl AuditLog {
+    pub fn new() -> AuditLog {
+        AuditLog::default()
+    }
+
+    pub fn load_config(&mut self, section: &Properties) -> Result<(), String> {
+        if let Some(enabled) = section.get("enabled") {
+            self.enabled = enabled != "false";
+        }
+        if let Some(hash_values) = section.get("hash_values") {
+            self.hash_values = hash_values == "true";
+        }
+        if let Some(memory) = section.get("memory") {
+            self.capacity = memory
+                .parse()
+                .map_err(|_| format!("Invalid memory '{}'", memory))?;
+        }
+        Ok(())
+    }
+
+    pub fn record(&mut self, mut event: AuditEvent) {
+        if !self.enabled {
+            return;
+        }
+        if self.hash_values {
+            event.value = event.value.map(|value| {
+                sha256(value.as_bytes())
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect()
+            });
+        }
+        log::info!("{}", serde_json::to_string(&event).unwrap());
+        if self.capacity == 0 {
+            return;
+        }
+        if self.recent.len() == self.capacity {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(event);
+    }
+
+    // Most recent first
+    pub fn query(&self, query: &AuditQuery, limit: usize) -> Vec<&AuditEvent> {
+        self.recent
+            .iter()
+            .rev()
+            .filter(|event| query.matches(event))
+            .take(limit)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(principal: &str, namespace: &str, status: u16) -> AuditEvent {
+        AuditEvent {
+            time: 0,
+            principal: Some(principal.to_string()),
+            endpoint: String::from("/w"),
+            namespace: namespace.to_string(),
+            value: Some(String::from("eA")),
+            status,
+            outcome: outcome(status),
+            remote: None,
+        }
+    }
+
+    #[test]
+    fn test_query_and_capacity() {
+        let mut audit = AuditLog {
+            capacity: 2,
+            hash_values: true,
+            ..AuditLog::default()
+        };
+        audit.record(event("a", "feeds/x", 200));
+        audit.record(event("b", "feeds/y", 403));
+        audit.record(event("a", "other", 200));
+
+        let all = audit.query(&AuditQuery::default(), 10);
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].namespace, "other");
+        assert_eq!(all[0].value.as_ref().unwrap().len(), 64);
+
+        let denied = AuditQuery {
+            outcome: Some(String::from("denied")),
+            namespace: Some(String::from("feeds/")),
+            ..AuditQuery::default()
+        };
+        assert_eq!(audit.query(&denied, 10)[0].principal.as_deref(), Some("b"));
+        assert_eq!(split_path("/w/my/namespace"), (String::from("/w"), String::from("my/namespace")));
+    }
+}
@@ -0,0 +1,211 @@
+use actix_web::dev::ServiceRequest;
+use actix_web::http::Method;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use chrono::Utc;
+use std::sync::{Arc, Mutex};
+
+use crate::acl::{reserved_allows, Permission, Principal};
+use crate::client_cert::ClientCertificate;
+use crate::jwt::looks_like_jwt;
+use crate::local_socket::{self, LocalConnection};
+use crate::ratelimit::too_many_requests;
+use crate::error::Error;
+use crate::SharedState;
+
+fn unauthorized(message: &str) -> HttpResponse {
+    Error::Unauthorized(message.to_string()).response()
+}
+
+fn forbidden(message: String) -> HttpResponse {
+    Error::Forbidden(message).response()
+}
+
+// What a request is counted as in the key usage
+fn request_permission(req: &ServiceRequest) -> Permission {
+    let path = req.path();
+    if req.method() == Method::DELETE || path.starts_with("/d/") {
+        Permission::Delete
+    } else if path.starts_with("/w/") || path == "/wb" || path == "/ws" {
+        Permission::Write
+    } else {
+        Permission::Read
+    }
+}
+
+// Who the credentials of the request belong to: a key, which may be sent as 'Bearer <key>',
+// a JSON Web Token when [jwt] is configured, or without any, a client certificate listed
+// in [client_certificates] or the unix_socket_rules of the local socket. A key that is given
+// must be valid.
+fn identify(sharedstate: &mut SharedState, req: &ServiceRequest) -> Result<Option<Principal>, HttpResponse> {
+    let http_header_auth = match req.headers().get("Authorization") {
+        Some(header) => header,
+        None => {
+            let extensions = req.extensions();
+            if let Some(cert) = extensions.get::<ClientCertificate>() {
+                return Ok(sharedstate.certificates.principal(cert));
+            }
+            if extensions.get::<LocalConnection>().is_some() {
+                return Ok(sharedstate.local_rules.as_deref().map(local_socket::principal));
+            }
+            return Ok(None);
+        }
+    };
+    let authkey = http_header_auth
+        .to_str()
+        .map_err(|_| unauthorized("Invalid Authorization header."))?;
+    let authkey = authkey.strip_prefix("Bearer ").unwrap_or(authkey);
+    if let Some(verifier) = &sharedstate.jwt {
+        if looks_like_jwt(authkey) {
+            return verifier
+                .verify(authkey, Utc::now().timestamp())
+                .map(Some)
+                .map_err(|e| unauthorized(&e));
+        }
+    }
+    let principal = match sharedstate.db.apikeys.get(authkey) {
+        Some(apikey) => apikey.principal(),
+        None => return Err(unauthorized("API key not found.")),
+    };
+    sharedstate
+        .db
+        .apikeys
+        .record_use(&principal.id, request_permission(req));
+    Ok(Some(principal))
+}
+
+// Run by the middleware on every request. With authenticate=false rules are not enforced,
+// but admin rights and rate limits still count. Without credentials the request gets no
+// principal, unless authenticate=false, and the handlers needing one answer 401.
+pub fn resolve(state: &Arc<Mutex<SharedState>>, req: &ServiceRequest) -> Result<Option<Principal>, HttpResponse> {
+    let sharedstate = &mut *state.lock().unwrap();
+
+    let mut principal = match identify(sharedstate, req)? {
+        Some(principal) => principal,
+        None if sharedstate.authenticate => return Ok(None),
+        None => Principal::anonymous(),
+    };
+    if !sharedstate.authenticate {
+        principal.rules = Principal::anonymous().rules;
+    }
+
+    let write = request_permission(req) != Permission::Read;
+    let now = Utc::now().timestamp_millis() as f64 / 1000.0;
+    sharedstate
+        .limits
+        .check_rate(&principal, write, now)
+        .map_err(|retry_after| too_many_requests("Rate limit exceeded.", retry_after).response())?;
+    Ok(Some(principal))
+}
+
+fn current(req: &HttpRequest) -> Result<Principal, Error> {
+    req.extensions()
+        .get::<Principal>()
+        .cloned()
+        .ok_or_else(|| Error::Unauthorized(String::from("Please add the API key in the Authorization headers.")))
+}
+
+pub fn principal(req: &HttpRequest) -> Result<Principal, HttpResponse> {
+    current(req).map_err(|e| e.response())
+}
+
+fn action(permission: Permission) -> &'static str {
+    match permission {
+        Permission::Read => "read",
+        Permission::Write => "write to",
+        Permission::Delete => "delete",
+    }
+}
+
+pub fn authorize(req: &HttpRequest, permission: Permission, namespace: &str) -> Result<Principal, HttpResponse> {
+    check(req, permission, namespace).map_err(|e| e.response())
+}
+
+// Same as authorize, for bulk requests reporting the error of each item
+pub fn check(req: &HttpRequest, permission: Permission, namespace: &str) -> Result<Principal, Error> {
+    let principal = current(req)?;
+    if !reserved_allows(principal.admin, permission, namespace) {
+        return Err(Error::Forbidden(format!(
+            "Namespace /{} is reserved to the database.",
+            namespace
+        )));
+    }
+    if !principal.allows(permission, namespace) {
+        return Err(Error::Forbidden(format!(
+            "Cannot {} namespace: /{}",
+            action(permission),
+            namespace
+        )));
+    }
+    Ok(principal)
+}
+
+// For requests spanning every namespace
+pub fn authorize_all(req: &HttpRequest, permission: Permission) -> Result<Principal, HttpResponse> {
+    let principal = principal(req)?;
+    if !principal.allows_all(permission) {
+        return Err(forbidden(format!(
+            "Cannot {} every namespace.",
+            action(permission)
+        )));
+    }
+    Ok(principal)
+}
+
+// Key management always needs an admin key, even when authenticate=false
+pub fn require_admin(req: &HttpRequest) -> Result<Principal, HttpResponse> {
+    let principal = principal(req)?;
+    if !principal.admin {
+        return Err(forbidden(String::from("This API key cannot manage keys.")));
+    }
+    Ok(principal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::parse_rules;
+    use crate::ratelimit::Limits;
+    use actix_web::http::header::HeaderValue;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    fn shared_state(authenticate: bool) -> (Arc<Mutex<SharedState>>, String) {
+        let mut sharedstate = SharedState::new();
+        sharedstate.authenticate = authenticate;
+        let rules = parse_rules("allow r *").unwrap();
+        let (_, authkey) = sharedstate.db.apikeys.create("reader", rules, false, Limits::default());
+        (Arc::new(Mutex::new(sharedstate)), authkey)
+    }
+
+    #[test]
+    fn test_resolve() {
+        // No credentials: no principal, the handlers answer 401
+        let (state, authkey) = shared_state(true);
+        let req = TestRequest::with_uri("/r/feeds").to_srv_request();
+        assert_eq!(resolve(&state, &req).unwrap(), None);
+        let (req, _) = req.into_parts();
+        assert_eq!(principal(&req).unwrap_err().status(), StatusCode::UNAUTHORIZED);
+
+        // A key that is given must be valid, even with authenticate=false
+        let (open, _) = shared_state(false);
+        let req = TestRequest::with_uri("/r/feeds").header("Authorization", "wrong").to_srv_request();
+        assert_eq!(resolve(&open, &req).unwrap_err().status(), StatusCode::UNAUTHORIZED);
+
+        let req = TestRequest::with_uri("/r/feeds")
+            .header("Authorization", HeaderValue::from_bytes(b"caf\xc3\xa9").unwrap())
+            .to_srv_request();
+        assert_eq!(resolve(&state, &req).unwrap_err().status(), StatusCode::UNAUTHORIZED);
+
+        // Valid, but read only
+        let req = TestRequest::with_uri("/w/feeds")
+            .header("Authorization", format!("Bearer {}", authkey))
+            .to_srv_request();
+        let reader = resolve(&state, &req).unwrap().unwrap();
+        assert_eq!(request_permission(&req), Permission::Write);
+        req.extensions_mut().insert(reader);
+        let (req, _) = req.into_parts();
+        assert!(authorize(&req, Permission::Read, "feeds").is_ok());
+        let denied = authorize(&req, Permission::Write, "feeds").unwrap_err();
+        assert_eq!(denied.status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,139 @@
+use ini::ini::Properties;
+use openssl::nid::Nid;
+use openssl::ssl::SslRef;
+use openssl::x509::X509Ref;
+
+use crate::acl::{parse_rules, Principal, Rule};
+use crate::ratelimit::Limits;
+
+// Names of the client certificate of a TLS connection, verified against ssl_client_ca:
+// the subject common name, then the DNS, email and URI subject alternative names.
+// Stored in the extensions of every request made over the connection.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClientCertificate {
+    pub names: Vec<String>,
+}
+
+impl ClientCertificate {
+    pub fn from_ssl(ssl: &SslRef) -> ClientCertificate {
+        match ssl.peer_certificate() {
+            Some(cert) => ClientCertificate::from_x509(&cert),
+            None => ClientCertificate::default(),
+        }
+    }
+
+    fn from_x509(cert: &X509Ref) -> ClientCertificate {
+        let mut names = Vec::new();
+        for entry in cert.subject_name().entries_by_nid(Nid::COMMONNAME) {
+            if let Ok(name) = entry.data().as_utf8() {
+                names.push(name.to_string());
+            }
+        }
+        if let Some(alt_names) = cert.subject_alt_names() {
+            for alt_name in alt_names.iter() {
+                if let Some(name) = alt_name.dnsname().or_else(|| alt_name.email()).or_else(|| alt_name.uri()) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        ClientCertificate { names }
+    }
+}
+
+// [client_certificates] section of the configuration: a certificate subject common name or
+// alternative name, then its rules like in [apikeys]
+// ingest.example.com = allow rw feeds/
+#[derive(Default)]
+pub struct CertificateRules {
+    rules: Vec<(String, Vec<Rule>)>,
+}
+
+impl CertificateRules {
+    pub fn new() -> CertificateRules {
+        CertificateRules::default()
+    }
+
+    pub fn load_config(&mut self, section: &Properties) -> Result<(), String> {
+        for (name, rules) in section.iter() {
+            let rules = parse_rules(rules).map_err(|e| format!("Certificate {}: {}", name, e))?;
+            self.rules.push((name.to_string(), rules));
+        }
+        Ok(())
+    }
+
+    // The first certificate name with rules wins. Certificates never manage keys.
+    pub fn principal(&self, cert: &ClientCertificate) -> Option<Principal> {
+        cert.names.iter().find_map(|name| {
+            self.rules
+                .iter()
+                .find(|(configured, _)| configured == name)
+                .map(|(_, rules)| Principal {
+                    id: format!("cert:{}", name),
+                    rules: rules.clone(),
+                    admin: false,
+                    limits: Limits::default(),
+                })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509NameBuilder, X509};
+
+    fn certificate(common_name: &str, dns: &str) -> ClientCertificate {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, common_name).unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        let alt_names = SubjectAlternativeName::new()
+            .dns(dns)
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(alt_names).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        ClientCertificate::from_x509(&builder.build())
+    }
+
+    #[test]
+    fn test_principal_by_name() {
+        let mut ini = ini::Ini::new();
+        ini.with_section(Some("client_certificates"))
+            .set("ingest", "allow rw feeds/")
+            .set("reader.example.com", "allow r *");
+        let mut rules = CertificateRules::new();
+        rules.load_config(ini.section(Some("client_certificates")).unwrap()).unwrap();
+        assert_eq!(rules.rules.len(), 2);
+
+        let cert = certificate("ingest", "ingest.example.com");
+        assert_eq!(cert.names, vec!["ingest", "ingest.example.com"]);
+        let principal = rules.principal(&cert).unwrap();
+        assert_eq!(principal.id, "cert:ingest");
+        assert_eq!(principal.rules, parse_rules("allow rw feeds/").unwrap());
+        assert!(!principal.admin);
+
+        // By its alternative name
+        let principal = rules.principal(&certificate("reader", "reader.example.com")).unwrap();
+        assert_eq!(principal.id, "cert:reader.example.com");
+
+        assert!(rules.principal(&certificate("unknown", "unknown.example.com")).is_none());
+        assert!(rules.principal(&ClientCertificate::default()).is_none());
+
+        let mut invalid = ini::Ini::new();
+        invalid.with_section(Some("client_certificates")).set("ingest", "allow x feeds/");
+        let mut rules = CertificateRules::new();
+        assert!(rules.load_config(invalid.section(Some("client_certificates")).unwrap()).is_err());
+    }
+}
@@ -1,39 +1,58 @@
-use serde::Serialize;
+use chrono::Utc;
 use std::collections::HashMap;
 
-use crate::attribute::Attribute;
-use crate::db_log::log_attribute;
+use crate::apikeys::KeyStore;
+use crate::anomaly::{detect, Spike};
+use crate::attribute::{stats_bucket, Attribute, NOW};
+use crate::db_log::{log_attribute, log_batch};
+use crate::error::Error;
+use crate::heavy_hitters::{SpaceSaving, TopBy, TopItem, DEFAULT_CAPACITY};
+use crate::namespace::{Namespace, NamespaceStats, SortBy};
+use crate::sighting_configure::{Configuration, NamespaceSettings};
+use crate::time_index::{Cursor, TimeFilter};
 
 pub struct Database {
     db_path: String,
     // Where are DB is stored on disk
-    hashtable: HashMap<String, HashMap<String, Attribute>>
+    hashtable: HashMap<String, Namespace>,
+    // Heavy hitters counters kept per namespace and across all non internal namespaces
+    top_capacity: usize,
+    global_top: SpaceSaving,
+    config: Configuration,
+    pub apikeys: KeyStore,
+    // Writes kept for db_log until the batch they belong to is committed
+    batch: Option<Vec<(String, Attribute)>>,
 }
 
-#[derive(Serialize)]
-pub struct DbError {
-    error: String,
-    namespace: String,
-    value: String,
+// Internal namespaces (_all, _shadow, _config...) are not client data
+fn is_internal(namespace: &str) -> bool {
+    namespace.starts_with('_')
 }
 
 impl Database {
     pub fn new() -> Database {
-        let mut db = Database {
+        Database {
             db_path: String::from(""),
             hashtable: HashMap::new(),
-        };
-        // We initialize the default apikey: 'changeme'
-        let attr = Attribute::new("");
-        let mut tmphash = HashMap::new();
-        tmphash.insert("".to_string(), attr);
-        db.hashtable
-            .insert("_config/acl/apikeys/changeme".to_string(), tmphash);
-        db
+            top_capacity: DEFAULT_CAPACITY,
+            global_top: SpaceSaving::new(DEFAULT_CAPACITY),
+            config: Configuration::new(),
+            apikeys: KeyStore::new(),
+            batch: None,
+        }
     }
     pub fn set_db_path(&mut self, path: String) {
         self.db_path = path;
     }
+    // Only applies to namespaces created afterwards, so call it before writing
+    pub fn set_top_capacity(&mut self, capacity: usize) {
+        self.top_capacity = capacity;
+        self.global_top = SpaceSaving::new(capacity);
+    }
+    // Same as set_top_capacity, namespace settings are picked when the namespace is created
+    pub fn set_configuration(&mut self, config: Configuration) {
+        self.config = config;
+    }
     // Return the count of the written value
     pub fn write(
         &mut self,
@@ -43,51 +62,50 @@ impl Database {
         write_consensus: bool,
     ) -> u128 {
         let (attr, new_value_to_path) = match self.hashtable.get_mut(path) {
-            Some(valuestable) => {
-                match valuestable.get_mut(value) {
-                    // New attribute in a path that exists
-                    None => {
-                        let mut attr = Attribute::new(value);
-                        attr.increment(timestamp);
-                        valuestable.insert(value.to_string(), attr.clone());
-                        (attr, false)
-                    }
-                    // Update to an existing attribute
-                    Some(attr) => {
-                        attr.increment(timestamp);
-                        (attr.clone(), true)
-                    }
-                }
-            }
+            // Update to an existing attribute, or new attribute in a path that exists
+            Some(namespace) => namespace.increment(value, timestamp),
             None => {
                 // New value to a path that does not exist
-                let mut newvaluestable = HashMap::new();
-                let mut attr = Attribute::new(value);
-                attr.increment(timestamp);
-                newvaluestable.insert(value.to_string(), attr.clone());
-                self.hashtable.insert(path.to_string(), newvaluestable);
+                let mut namespace =
+                    Namespace::new(self.top_capacity, self.config.get(path).clone());
+                let (attr, _) = namespace.increment(value, timestamp);
+                self.hashtable.insert(path.to_string(), namespace);
                 (attr, true)
             }
         };
-
+        if !is_internal(path) {
+            self.global_top.offer(value, 1);
+        }
 
         if new_value_to_path && write_consensus {
             // Check for consensus
             // Do we have the value in _all? If not then
             // we add it and consensus is the count of the
             // value from _all.
-            self.write(&"_all".to_string(), value, 0, false);
+            self.write("_all", value, NOW, false);
+        }
+        match &mut self.batch {
+            Some(batch) => batch.push((path.to_string(), attr.clone())),
+            None => log_attribute(path, &attr),
         }
-        log_attribute(path, &attr);
         attr.count
     }
 
+    // The writes until commit_batch are logged together
+    pub fn begin_batch(&mut self) {
+        self.batch = Some(Vec::new());
+    }
+
+    pub fn commit_batch(&mut self) {
+        if let Some(batch) = self.batch.take() {
+            log_batch(&batch);
+        }
+    }
+
     pub fn new_consensus(&mut self, path: &str, value: &str, consensus_count: u128) -> u128 {
-        let valuestable = self.hashtable.get_mut(&path.to_string()).unwrap();
-        let attr = valuestable.get_mut(&value.to_string());
-        match attr {
-            Some(_attr) => {
-                let iattr = valuestable.get_mut(&value.to_string()).unwrap();
+        let valuestable = &mut self.hashtable.get_mut(path).unwrap().attributes;
+        match valuestable.get_mut(value) {
+            Some(iattr) => {
                 iattr.set_consensus(consensus_count);
                 iattr.consensus
             }
@@ -95,10 +113,10 @@ impl Database {
         }
     }
     pub fn get_count(&mut self, path: &str, value: &str) -> u128 {
-        let valuestable = self.hashtable.get_mut(&path.to_string());
+        let valuestable = self.hashtable.get_mut(path);
         match valuestable {
             Some(valuestable) => {
-                let attr = valuestable.get_mut(&value.to_string());
+                let attr = valuestable.attributes.get_mut(value);
                 match attr {
                     Some(attr) => attr.count(),
                     None => 0,
@@ -108,27 +126,34 @@ impl Database {
         }
     }
     pub fn namespace_exists(&mut self, namespace: &str) -> bool {
-        let valuestable = self.hashtable.get_mut(&namespace.to_string());
+        let valuestable = self.hashtable.get_mut(namespace);
         valuestable.is_some()
     }
 
-    pub fn get_namespace_attrs(&mut self, namespace: &str) -> String {
-        let valuestable = self.hashtable.get_mut(&namespace.to_string());
+    // Whether the value exists in the namespace and falls in the filter time window
+    pub fn get_namespace_attrs(
+        &mut self,
+        namespace: &str,
+        filter: &TimeFilter,
+        sort: Option<SortBy>,
+    ) -> Result<String, Error> {
+        let valuestable = self.hashtable.get_mut(namespace);
 
         match valuestable {
             Some(valuestable) => {
-                let mut response: HashMap<&str, Vec<&Attribute>> = HashMap::new();
-                response.insert("attributes", valuestable.iter().map(|(_, attr)| attr).collect::<Vec<_>>());
-                serde_json::to_string(&response).unwrap()
-            }
-            None => {
-                let err = serde_json::to_string(&DbError {
-                    error: String::from("Namespace not found"),
-                    namespace: namespace.to_string(),
-                    value: "".to_string(),
-                });
-                err.unwrap()
+                let now = Utc::now().timestamp();
+                let attributes: Box<dyn Iterator<Item = &Attribute>> = match sort {
+                    Some(sort) => Box::new(valuestable.sorted(filter, sort, now).into_iter()),
+                    None => valuestable.filtered(filter),
+                };
+                let attributes = attributes
+                    .map(|attr| attr.to_value_with(attr.read_fields(now, &valuestable.settings)))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                let mut response: HashMap<&str, Vec<serde_json::Value>> = HashMap::new();
+                response.insert("attributes", attributes);
+                Ok(serde_json::to_string(&response)?)
             }
+            None => Err(Error::NamespaceNotFound(namespace.to_string())),
         }
     }
 
@@ -138,12 +163,36 @@ impl Database {
         value: &str,
         with_stats: bool,
         consensus_count: u128,
-    ) -> String {
-        let valuestable = self.hashtable.get_mut(&path.to_string());
+    ) -> Result<String, Error> {
+        self.get_attr_with(path, value, consensus_count, |attr, settings| {
+            let now = Utc::now().timestamp();
+            let mut fields = attr.read_fields(now, settings);
+            if with_stats {
+                fields.push(("spike", serde_json::to_value(attr.spike(now, settings))?));
+                Ok(attr.serialize_with_stats(fields)?)
+            } else {
+                Ok(attr.serialize_with(fields)?)
+            }
+        })
+    }
+
+    // Looks the attribute up, updates its consensus and lets `render` serialize it.
+    // Fails when the namespace or the value does not exist.
+    pub fn get_attr_with<F, T>(
+        &mut self,
+        path: &str,
+        value: &str,
+        consensus_count: u128,
+        render: F,
+    ) -> Result<T, Error>
+    where
+        F: FnOnce(&Attribute, &NamespaceSettings) -> Result<T, Error>,
+    {
+        let valuestable = self.hashtable.get_mut(path);
 
         match valuestable {
             Some(valuestable) => {
-                let attr = valuestable.get_mut(&value.to_string());
+                let attr = valuestable.attributes.get_mut(value);
                 match attr {
                     Some(attr) => {
                         if attr.ttl > 0 {
@@ -151,37 +200,144 @@ impl Database {
                         }
                         attr.consensus = consensus_count;
 
-                        if with_stats {
-                            attr.serialize_with_stats().unwrap()
-                        } else {
-                            serde_json::to_string(attr).unwrap()
-                        }
-                    }
-                    None => {
-                        let err = serde_json::to_string(&DbError {
-                            error: String::from("Value not found"),
-                            namespace: path.to_string(),
-                            value: value.to_string(),
-                        });
-                        err.unwrap()
+                        render(attr, &valuestable.settings)
                     }
+                    None => Err(Error::ValueNotFound {
+                        namespace: path.to_string(),
+                        value: value.to_string(),
+                    }),
                 }
             }
-            None => {
-                let err = serde_json::to_string(&DbError {
-                    error: String::from("Path not found"),
-                    namespace: path.to_string(),
-                    value: value.to_string(),
-                });
-                err.unwrap()
+            None => Err(Error::NamespaceNotFound(path.to_string())),
+        }
+    }
+
+    // Values of the namespace spiking in the current stats bucket, most anomalous first,
+    // along with the spike computed on the namespace histogram.
+    pub fn get_anomalies(&self, namespace: &str, now: i64) -> Option<(Spike, Vec<(&str, Spike)>)> {
+        let valuestable = self.hashtable.get(namespace)?;
+        let settings = &valuestable.settings;
+
+        let namespace_spike = detect(
+            &valuestable.stats.stats,
+            now,
+            settings.stats_granularity,
+            &settings.spike,
+        );
+        // Only values seen in the current bucket can be spiking
+        let filter = TimeFilter {
+            last_seen_after: Some(stats_bucket(now, settings.stats_granularity)),
+            ..TimeFilter::default()
+        };
+        let mut values: Vec<(&str, Spike)> = valuestable
+            .filtered(&filter)
+            .map(|attr| (attr.value.as_str(), attr.spike(now, settings)))
+            .filter(|(_, spike)| spike.spiking)
+            .collect();
+        values.sort_by(|a, b| b.1.score.total_cmp(&a.1.score));
+        Some((namespace_spike, values))
+    }
+
+    // Names of the namespaces starting with prefix, sorted
+    pub fn namespaces(&self, prefix: &str) -> Vec<String> {
+        let mut namespaces: Vec<String> = self
+            .hashtable
+            .keys()
+            .filter(|namespace| namespace.starts_with(prefix))
+            .cloned()
+            .collect();
+        namespaces.sort();
+        namespaces
+    }
+
+    // Up to n values of the namespace in the filter time window, by last_seen, after the cursor
+    // of the previous page. Empty once done, or when the namespace does not exist anymore.
+    pub fn namespace_page(
+        &self,
+        namespace: &str,
+        after: Option<&Cursor>,
+        filter: &TimeFilter,
+        n: usize,
+    ) -> Vec<(&Cursor, &Attribute)> {
+        let valuestable = match self.hashtable.get(namespace) {
+            Some(valuestable) => valuestable,
+            None => return Vec::new(),
+        };
+        let mut page = Vec::new();
+        for cursor in valuestable.time_index.by_last_seen(after, filter) {
+            if page.len() == n {
+                break;
+            }
+            if let Some(attr) = valuestable.attributes.get(&*cursor.1) {
+                if filter.matches(attr) {
+                    page.push((cursor, attr));
+                }
             }
         }
-        // return String::from(""); // unreachable statement, however I want to make it clear this is our default
+        page
+    }
+
+    pub fn attribute(&self, namespace: &str, value: &str) -> Option<&Attribute> {
+        self.hashtable
+            .get(namespace)
+            .and_then(|valuestable| valuestable.attributes.get(value))
     }
 
-    pub fn delete(&mut self, namespace: &str) -> bool {
-        let res = self.hashtable.remove(&namespace.to_string());
-        res.is_some()
+    pub fn get_namespace_stats(&self, namespace: &str) -> Option<NamespaceStats> {
+        self.hashtable
+            .get(namespace)
+            .map(|valuestable| valuestable.stats.clone())
+    }
+
+    // Most sighted values of a namespace, or of all the non internal namespaces when None.
+    // Returns None when the namespace does not exist.
+    pub fn get_top(&self, namespace: Option<&str>, n: usize, by: TopBy, now: i64) -> Option<Vec<TopItem>> {
+        if let Some(namespace) = namespace {
+            return self
+                .hashtable
+                .get(namespace)
+                .map(|valuestable| valuestable.top(n, by, now));
+        }
+
+        match by {
+            // Across namespaces we only have the Space-Saving estimates
+            TopBy::Count => Some(self.global_top.top(n)),
+            TopBy::RecentCount { hours } => {
+                let mut counts: HashMap<&str, u128> = HashMap::new();
+                for (_, valuestable) in self.hashtable.iter().filter(|(path, _)| !is_internal(path)) {
+                    for (value, count) in valuestable.recent_counts(now, hours) {
+                        *counts.entry(value).or_insert(0) += count;
+                    }
+                }
+                let mut items: Vec<TopItem> = counts
+                    .into_iter()
+                    .filter(|(_, count)| *count > 0)
+                    .map(|(value, count)| TopItem {
+                        value: value.to_string(),
+                        count,
+                        error: None,
+                    })
+                    .collect();
+                items.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+                items.truncate(n);
+                Some(items)
+            }
+        }
+    }
+
+    pub fn delete(&mut self, namespace: &str) -> Result<(), Error> {
+        let res = self.hashtable.remove(namespace);
+        if let Some(valuestable) = &res {
+            if !is_internal(namespace) {
+                for attr in valuestable.attributes.values() {
+                    self.global_top.remove(&attr.value, attr.count);
+                }
+            }
+        }
+        match res {
+            Some(_) => Ok(()),
+            None => Err(Error::NamespaceNotFound(namespace.to_string())),
+        }
     }
 }
 
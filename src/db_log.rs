@@ -3,3 +3,8 @@ use crate::attribute::Attribute;
 pub fn log_attribute(path: &str, attribute: &Attribute) {
     log::info!("{} | {}", path, serde_json::to_string(attribute).unwrap())
 }
+
+// Every write of an all_or_nothing bulk write, as one record: _batch | [[path, attribute], ...]
+pub fn log_batch(writes: &[(String, Attribute)]) {
+    log::info!("_batch | {}", serde_json::to_string(writes).unwrap())
+}
@@ -0,0 +1,159 @@
+use actix_web::error::{JsonPayloadError, ResponseError};
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse};
+use serde::Serialize;
+use std::fmt;
+
+// Every error a request can end with. They are answered with their HTTP status and a JSON body
+// carrying a machine-readable code next to the message:
+// {"code": "value_not_found", "message": "Value not found", "namespace": "my/namespace", "value": "eA"}
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NamespaceNotFound(String),
+    ValueNotFound { namespace: String, value: String },
+    // Anything else that does not exist, such as an API key
+    NotFound(String),
+    PayloadTooLarge(String),
+    TooManyRequests { message: String, retry_after: u64 },
+    Internal(String),
+}
+
+#[derive(Serialize)]
+pub struct ErrorBody<'a> {
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespace: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u64>,
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::BadRequest(_) => "bad_request",
+            Error::Unauthorized(_) => "unauthorized",
+            Error::Forbidden(_) => "forbidden",
+            Error::NamespaceNotFound(_) => "namespace_not_found",
+            Error::ValueNotFound { .. } => "value_not_found",
+            Error::NotFound(_) => "not_found",
+            Error::PayloadTooLarge(_) => "payload_too_large",
+            Error::TooManyRequests { .. } => "too_many_requests",
+            Error::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::NamespaceNotFound(_) | Error::ValueNotFound { .. } | Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody<'_> {
+        let (namespace, value) = match self {
+            Error::NamespaceNotFound(namespace) => (Some(namespace.as_str()), None),
+            Error::ValueNotFound { namespace, value } => (Some(namespace.as_str()), Some(value.as_str())),
+            _ => (None, None),
+        };
+        ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+            namespace,
+            value,
+            retry_after: match self {
+                Error::TooManyRequests { retry_after, .. } => Some(*retry_after),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status());
+        if let Error::TooManyRequests { retry_after, .. } = self {
+            response.header("Retry-After", retry_after.to_string());
+        }
+        response.json(self.body())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NamespaceNotFound(_) => write!(f, "Namespace not found"),
+            Error::ValueNotFound { .. } => write!(f, "Value not found"),
+            Error::BadRequest(message)
+            | Error::Unauthorized(message)
+            | Error::Forbidden(message)
+            | Error::NotFound(message)
+            | Error::PayloadTooLarge(message)
+            | Error::TooManyRequests { message, .. }
+            | Error::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl ResponseError for Error {
+    fn error_response(&self) -> HttpResponse {
+        self.response()
+    }
+
+    // The default replaces the body with the message as text
+    fn render_response(&self) -> HttpResponse {
+        self.response()
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Internal(e.to_string())
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        Error::Internal(e.to_string())
+    }
+}
+
+// Posted JSON that cannot be read, or is over post_limit
+pub fn json_error(e: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    match e {
+        JsonPayloadError::Overflow => Error::PayloadTooLarge(String::from("Payload over post_limit.")),
+        e => Error::BadRequest(e.to_string()),
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_body() {
+        let error = Error::ValueNotFound {
+            namespace: String::from("my/namespace"),
+            value: String::from("eA"),
+        };
+        assert_eq!(error.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            serde_json::to_string(&error.body()).unwrap(),
+            r#"{"code":"value_not_found","message":"Value not found","namespace":"my/namespace","value":"eA"}"#
+        );
+        let error = Error::TooManyRequests {
+            message: String::from("Rate limit exceeded."),
+            retry_after: 3,
+        };
+        assert_eq!(error.response().headers().get("Retry-After").unwrap(), "3");
+    }
+}
@@ -0,0 +1,296 @@
+use actix_web::web::Bytes;
+use futures::{Async, Poll, Stream};
+use qstring::QString;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::acl::{reserved_allows, Permission, Principal};
+use crate::attribute::Attribute;
+use crate::error::Error;
+use crate::time_index::{Cursor, TimeFilter};
+use crate::SharedState;
+
+// Export of the namespaces under a prefix (/export/<prefix>), streamed CHUNK_SIZE values at a
+// time, each chunk under the lock, so a dump never builds the whole namespace in memory: a
+// namespace is paged through by last_seen, so a value sighted during the export may come again
+// with its new count. Namespaces the principal cannot read are left out.
+//
+// format=ndjson|csv, stats=true, tags=true, and last_seen_after, last_seen_before (or the
+// other bounds of TimeFilter). Sources are not tracked by the database, so they cannot be
+// exported.
+
+pub const CHUNK_SIZE: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Ndjson,
+    Csv,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Ndjson => "application/x-ndjson",
+            Format::Csv => "text/csv",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExportOptions {
+    pub format: Format,
+    pub stats: bool,
+    pub tags: bool,
+    pub filter: TimeFilter,
+}
+
+impl ExportOptions {
+    pub fn from_query(query_string: &QString) -> Result<ExportOptions, Error> {
+        let flag = |name: &str| query_string.get(name).is_some_and(|v| v != "false");
+        if flag("sources") {
+            return Err(Error::BadRequest(String::from(
+                "Sources are not tracked, they cannot be exported.",
+            )));
+        }
+        let format = match query_string.get("format").unwrap_or("ndjson") {
+            "ndjson" => Format::Ndjson,
+            "csv" => Format::Csv,
+            _ => return Err(Error::BadRequest(String::from("format= must be either ndjson or csv."))),
+        };
+        Ok(ExportOptions {
+            format,
+            stats: flag("stats"),
+            tags: flag("tags"),
+            filter: TimeFilter::from_query(query_string)?,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct Row<'a> {
+    namespace: &'a str,
+    value: &'a str,
+    first_seen: i64,
+    last_seen: i64,
+    count: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<&'a BTreeMap<i64, u128>>,
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn csv_header(options: &ExportOptions) -> String {
+    let mut header = String::from("namespace,value,first_seen,last_seen,count");
+    if options.tags {
+        header.push_str(",tags");
+    }
+    if options.stats {
+        header.push_str(",stats");
+    }
+    header.push('\n');
+    header
+}
+
+fn write_row(out: &mut Vec<u8>, options: &ExportOptions, namespace: &str, attr: &Attribute) {
+    let row = Row {
+        namespace,
+        value: &attr.value,
+        first_seen: attr.first_seen.timestamp(),
+        last_seen: attr.last_seen.timestamp(),
+        count: attr.count,
+        tags: if options.tags { Some(&attr.tags) } else { None },
+        stats: if options.stats { Some(&attr.stats) } else { None },
+    };
+    match options.format {
+        Format::Ndjson => out.extend(serde_json::to_vec(&row).unwrap()),
+        Format::Csv => {
+            let mut fields = vec![
+                csv_field(row.namespace),
+                csv_field(row.value),
+                row.first_seen.to_string(),
+                row.last_seen.to_string(),
+                row.count.to_string(),
+            ];
+            if let Some(tags) = row.tags {
+                fields.push(csv_field(tags));
+            }
+            // The stats histogram as a JSON object
+            if let Some(stats) = row.stats {
+                fields.push(csv_field(&serde_json::to_string(stats).unwrap()));
+            }
+            out.extend(fields.join(",").into_bytes());
+        }
+    }
+    out.push(b'\n');
+}
+
+pub struct ExportStream {
+    state: Arc<Mutex<SharedState>>,
+    options: ExportOptions,
+    namespaces: VecDeque<String>,
+    // Namespace being exported and the last value exported from it
+    current: Option<(String, Option<Cursor>)>,
+    header: bool,
+}
+
+impl ExportStream {
+    pub fn new(state: Arc<Mutex<SharedState>>, principal: &Principal, prefix: &str, options: ExportOptions) -> ExportStream {
+        let namespaces = state
+            .lock()
+            .unwrap()
+            .db
+            .namespaces(prefix)
+            .into_iter()
+            .filter(|namespace| {
+                reserved_allows(principal.admin, Permission::Read, namespace)
+                    && principal.allows(Permission::Read, namespace)
+            })
+            .collect();
+        ExportStream {
+            state,
+            header: options.format == Format::Csv,
+            options,
+            namespaces,
+            current: None,
+        }
+    }
+
+    // Up to CHUNK_SIZE rows, None once everything was exported
+    fn next_chunk(&mut self) -> Option<Vec<u8>> {
+        let mut out = Vec::new();
+        if self.header {
+            self.header = false;
+            out.extend(csv_header(&self.options).into_bytes());
+        }
+        let sharedstate = self.state.lock().unwrap();
+        let mut rows = 0;
+        while rows < CHUNK_SIZE {
+            let (namespace, cursor) = match &mut self.current {
+                Some(current) => current,
+                None => match self.namespaces.pop_front() {
+                    Some(namespace) => {
+                        self.current = Some((namespace, None));
+                        continue;
+                    }
+                    None => break,
+                },
+            };
+            let page = sharedstate
+                .db
+                .namespace_page(namespace, cursor.as_ref(), &self.options.filter, CHUNK_SIZE - rows);
+            if page.is_empty() {
+                self.current = None;
+                continue;
+            }
+            for (position, attr) in page {
+                write_row(&mut out, &self.options, namespace, attr);
+                *cursor = Some(position.clone());
+                rows += 1;
+            }
+        }
+        if out.is_empty() {
+            None
+        } else {
+            Some(out)
+        }
+    }
+}
+
+impl Stream for ExportStream {
+    type Item = Bytes;
+    type Error = actix_web::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, actix_web::Error> {
+        Ok(Async::Ready(self.next_chunk().map(Bytes::from)))
+    }
+}
+
+// Command line export: asks the running daemon and copies the export to the standard output
+pub fn run_client(url: &str, apikey: Option<&str>, insecure: bool) -> Result<(), String> {
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(insecure)
+        .timeout(None)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut request = client.get(url);
+    if let Some(apikey) = apikey {
+        request = request.header("Authorization", apikey);
+    }
+    let mut response = request.send().map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!(
+            "{}: {}",
+            response.status(),
+            response.text().unwrap_or_default()
+        ));
+    }
+    io::copy(&mut response, &mut io::stdout()).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::parse_rules;
+
+    #[test]
+    fn test_export_csv() {
+        let state = Arc::new(Mutex::new(SharedState::new()));
+        {
+            let db = &mut state.lock().unwrap().db;
+            db.write("feeds/a", "YQ", 1000, false);
+            db.write("feeds/a", "YQ", 2000, false);
+            db.write("feeds/b", "a,b", 5000, false);
+            db.write("other", "Yw", 1000, false);
+        }
+        let mut principal = Principal::anonymous();
+        principal.rules = parse_rules("allow r *, deny r feeds/b").unwrap();
+
+        let query = QString::from("format=csv&stats=true&last_seen_after=1500");
+        let options = ExportOptions::from_query(&query).unwrap();
+        let out: Vec<Bytes> = ExportStream::new(state.clone(), &principal, "feeds/", options)
+            .wait()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            String::from_utf8(out.concat()).unwrap(),
+            "namespace,value,first_seen,last_seen,count,stats\nfeeds/a,YQ,1000,2000,2,\"{\"\"0\"\":2}\"\n"
+        );
+
+        // Paged across chunks, each value once
+        {
+            let db = &mut state.lock().unwrap().db;
+            for i in 0..2500 {
+                db.write("big", &format!("v{}", i), 1000 + i % 7, false);
+            }
+        }
+        let options = ExportOptions::from_query(&QString::from("")).unwrap();
+        let chunks: Vec<Bytes> = ExportStream::new(state.clone(), &principal, "big", options)
+            .wait()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(chunks.len(), 3);
+        let mut values: Vec<String> = chunks
+            .concat()
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice::<serde_json::Value>(line).unwrap()["value"].as_str().unwrap().to_string())
+            .collect();
+        values.sort();
+        values.dedup();
+        assert_eq!(values.len(), 2500);
+
+        assert!(ExportOptions::from_query(&QString::from("sources=true")).is_err());
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+    }
+}
@@ -0,0 +1,133 @@
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+
+pub const DEFAULT_CAPACITY: usize = 1000;
+// recent_count looks at most this far back, ten years
+pub const MAX_RECENT_HOURS: i64 = 10 * 366 * 24;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TopBy {
+    Count,
+    // Sightings in the stats buckets covering the last `hours` hours, current one included
+    RecentCount { hours: i64 },
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct TopItem {
+    pub value: String,
+    pub count: u128,
+    // Upper bound of the overestimation of count, only when the count is approximated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<u128>,
+}
+
+#[derive(Clone, Copy)]
+struct Counter {
+    count: u128,
+    error: u128,
+}
+
+// Space-Saving (Metwally et al.): keeps at most `capacity` counters, any value
+// seen more than total/capacity times is guaranteed to be tracked.
+pub struct SpaceSaving {
+    capacity: usize,
+    counters: HashMap<String, Counter>,
+    ordered: BTreeSet<(u128, String)>,
+}
+
+impl SpaceSaving {
+    pub fn new(capacity: usize) -> SpaceSaving {
+        SpaceSaving {
+            capacity: capacity.max(1),
+            counters: HashMap::new(),
+            ordered: BTreeSet::new(),
+        }
+    }
+
+    fn set(&mut self, value: &str, counter: Counter) {
+        self.ordered.insert((counter.count, value.to_string()));
+        self.counters.insert(value.to_string(), counter);
+    }
+
+    fn take(&mut self, value: &str) -> Option<Counter> {
+        let counter = self.counters.remove(value)?;
+        self.ordered.remove(&(counter.count, value.to_string()));
+        Some(counter)
+    }
+
+    pub fn offer(&mut self, value: &str, weight: u128) {
+        if let Some(mut counter) = self.take(value) {
+            counter.count += weight;
+            self.set(value, counter);
+        } else if self.counters.len() < self.capacity {
+            self.set(value, Counter { count: weight, error: 0 });
+        } else {
+            // Evict the smallest counter, the newcomer inherits its count as error
+            let (min_count, min_value) = self.ordered.iter().next().cloned().unwrap();
+            self.take(&min_value);
+            self.set(
+                value,
+                Counter {
+                    count: min_count + weight,
+                    error: min_count,
+                },
+            );
+        }
+    }
+
+    pub fn remove(&mut self, value: &str, weight: u128) {
+        if let Some(mut counter) = self.take(value) {
+            if counter.count > weight {
+                counter.count -= weight;
+                counter.error = counter.error.min(counter.count);
+                self.set(value, counter);
+            }
+        }
+    }
+
+    // Tracked values, highest counts first
+    pub fn top(&self, n: usize) -> Vec<TopItem> {
+        self.ordered
+            .iter()
+            .rev()
+            .take(n)
+            .map(|(count, value)| TopItem {
+                value: value.clone(),
+                count: *count,
+                error: Some(self.counters[value].error),
+            })
+            .collect()
+    }
+}
+
+impl Default for SpaceSaving {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_space_saving_keeps_heavy_hitters() {
+        let mut top = SpaceSaving::new(2);
+        for _ in 0..10 {
+            top.offer("heavy", 1);
+        }
+        top.offer("a", 1);
+        top.offer("b", 1);
+        top.offer("c", 1);
+
+        let items = top.top(2);
+        assert_eq!(items[0].value, "heavy");
+        assert_eq!(items[0].count, 10);
+        assert_eq!(items[1].value, "c");
+        assert_eq!(items[1].count, 3);
+        assert_eq!(items[1].error, Some(2));
+
+        top.remove("heavy", 10);
+        assert_eq!(top.top(2).len(), 1);
+    }
+}
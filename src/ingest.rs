@@ -0,0 +1,366 @@
+use actix_web::web::Bytes;
+use actix_web::HttpRequest;
+use chrono::Utc;
+use futures::{Async, Poll, Stream};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use crate::acl::Permission;
+use crate::attribute::NOW;
+use crate::error::Error;
+use crate::{audit, auth, ratelimit, sighting_writer, SharedState};
+
+// Streamed bulk writes (/ws): one sighting per line, written in chunks of at most CHUNK_SIZE
+// lines, each under the lock, as the request is read: a chunk is also written whenever the
+// client has not sent more yet. The response is streamed as well, one JSON
+// line per item that could not be written and one per chunk with the progress so far:
+//
+// {"line":3,"status":"forbidden","message":"Cannot write to namespace: /other","namespace":"other","value":"YQ"}
+// {"lines":1000,"written":999,"failed":1,"done":false}
+
+pub const CHUNK_SIZE: usize = 1000;
+// Longer lines are reported and skipped rather than buffered
+pub const MAX_LINE: usize = 1024 * 1024;
+
+#[derive(Deserialize)]
+struct StreamedSighting {
+    namespace: String,
+    value: String,
+    timestamp: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct LineError<'a> {
+    line: usize,
+    status: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespace: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct Progress {
+    lines: usize,
+    written: usize,
+    failed: usize,
+    done: bool,
+}
+
+pub struct IngestStream<S> {
+    payload: S,
+    state: Arc<Mutex<SharedState>>,
+    req: HttpRequest,
+    buffer: Vec<u8>,
+    // Past MAX_LINE, until the end of the line
+    skipping: bool,
+    pending: Vec<(usize, Vec<u8>)>,
+    lines: usize,
+    written: usize,
+    failed: usize,
+    done: bool,
+}
+
+impl<S> IngestStream<S>
+where
+    S: Stream<Item = Bytes, Error = actix_web::error::PayloadError>,
+{
+    pub fn new(payload: S, state: Arc<Mutex<SharedState>>, req: HttpRequest) -> IngestStream<S> {
+        IngestStream {
+            payload,
+            state,
+            req,
+            buffer: Vec::new(),
+            skipping: false,
+            pending: Vec::new(),
+            lines: 0,
+            written: 0,
+            failed: 0,
+            done: false,
+        }
+    }
+
+    fn report(&mut self, out: &mut Vec<u8>, line: usize, e: &Error, namespace: Option<&str>, value: Option<&str>) {
+        self.failed += 1;
+        let error = LineError {
+            line,
+            status: e.code(),
+            message: e.to_string(),
+            namespace,
+            value,
+        };
+        out.extend(serde_json::to_vec(&error).unwrap());
+        out.push(b'\n');
+    }
+
+    fn push_line(&mut self, out: &mut Vec<u8>, line: Vec<u8>) {
+        self.lines += 1;
+        if line.iter().all(u8::is_ascii_whitespace) {
+            return;
+        }
+        self.pending.push((self.lines, line));
+        if self.pending.len() >= CHUNK_SIZE {
+            self.apply(out);
+        }
+    }
+
+    // Splits what was received into lines, the last one may not be complete yet
+    fn feed(&mut self, out: &mut Vec<u8>, bytes: &[u8]) {
+        let mut rest = bytes;
+        while let Some(end) = rest.iter().position(|&b| b == b'\n') {
+            let (line, next) = rest.split_at(end);
+            rest = &next[1..];
+            if self.skipping {
+                self.skipping = false;
+                self.lines += 1;
+                continue;
+            }
+            let mut full = std::mem::take(&mut self.buffer);
+            full.extend_from_slice(line);
+            if full.len() > MAX_LINE {
+                self.lines += 1;
+                let line = self.lines;
+                self.report(out, line, &Error::PayloadTooLarge(String::from("Line too long.")), None, None);
+                continue;
+            }
+            self.push_line(out, full);
+        }
+        if self.skipping {
+            return;
+        }
+        self.buffer.extend_from_slice(rest);
+        if self.buffer.len() > MAX_LINE {
+            self.buffer.clear();
+            self.skipping = true;
+            let line = self.lines + 1;
+            self.report(out, line, &Error::PayloadTooLarge(String::from("Line too long.")), None, None);
+        }
+    }
+
+    fn apply(&mut self, out: &mut Vec<u8>) {
+        let pending = std::mem::take(&mut self.pending);
+        let mut sightings = Vec::new();
+        for (line, bytes) in pending {
+            match serde_json::from_slice::<StreamedSighting>(&bytes) {
+                Ok(sighting) => sightings.push((line, sighting)),
+                Err(e) => self.report(out, line, &Error::BadRequest(e.to_string()), None, None),
+            }
+        }
+
+        let state = self.state.clone();
+        let sharedstate = &mut *state.lock().unwrap();
+        let checked: Vec<Result<(), Error>> = sightings
+            .iter()
+            .map(|(_, v)| {
+                auth::check(&self.req, Permission::Write, &v.namespace)?;
+                sighting_writer::validate(&v.namespace, &v.value)
+            })
+            .collect();
+
+        // The quotas are checked for the whole chunk
+        let mut new_values = HashSet::new();
+        let mut writes = 0;
+        for ((_, v), _) in sightings.iter().zip(&checked).filter(|(_, c)| c.is_ok()) {
+            writes += 1;
+            if sharedstate.db.get_count(&v.namespace, &v.value) == 0 {
+                new_values.insert((v.namespace.as_str(), v.value.as_str()));
+            }
+        }
+        let quota = match auth::principal(&self.req) {
+            Ok(principal) => sharedstate
+                .limits
+                .check_quota(&principal, writes, new_values.len() as u64, Utc::now().timestamp())
+                .map_err(|retry_after| ratelimit::too_many_requests("Daily write quota exceeded.", retry_after)),
+            Err(_) => Ok(()),
+        };
+
+        for ((line, v), c) in sightings.iter().zip(checked) {
+            let result = c.and(quota.clone()).and_then(|()| {
+                sighting_writer::write(&mut sharedstate.db, &v.namespace, &v.value, v.timestamp.unwrap_or(NOW))
+            });
+            let status = match &result {
+                Ok(_) => 200,
+                Err(e) => e.status().as_u16(),
+            };
+            sharedstate
+                .audit
+                .record(audit::item_event(&self.req, &v.namespace, &v.value, status));
+            match result {
+                Ok(_) => self.written += 1,
+                Err(e) => self.report(out, *line, &e, Some(&v.namespace), Some(&v.value)),
+            }
+        }
+        self.progress(out);
+    }
+
+    fn progress(&self, out: &mut Vec<u8>) {
+        let progress = Progress {
+            lines: self.lines,
+            written: self.written,
+            failed: self.failed,
+            done: self.done,
+        };
+        out.extend(serde_json::to_vec(&progress).unwrap());
+        out.push(b'\n');
+    }
+}
+
+impl<S> Stream for IngestStream<S>
+where
+    S: Stream<Item = Bytes, Error = actix_web::error::PayloadError>,
+{
+    type Item = Bytes;
+    type Error = actix_web::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, actix_web::Error> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+        let mut out = Vec::new();
+        // Reads until there is something to answer
+        while out.is_empty() {
+            match self.payload.poll()? {
+                Async::Ready(Some(bytes)) => self.feed(&mut out, &bytes),
+                Async::Ready(None) => {
+                    if !self.skipping && !self.buffer.is_empty() {
+                        let line = std::mem::take(&mut self.buffer);
+                        self.push_line(&mut out, line);
+                    }
+                    self.done = true;
+                    if self.pending.is_empty() {
+                        self.progress(&mut out);
+                    } else {
+                        self.apply(&mut out);
+                    }
+                }
+                Async::NotReady => {
+                    // Nothing more for now: what was received is written rather than waiting
+                    // for a full chunk
+                    if self.pending.is_empty() {
+                        return Ok(Async::NotReady);
+                    }
+                    self.apply(&mut out);
+                }
+            }
+        }
+        Ok(Async::Ready(Some(Bytes::from(out))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::{parse_rules, Principal};
+    use actix_web::test::TestRequest;
+    use futures::stream;
+
+    #[test]
+    fn test_ingest_lines() {
+        let state = Arc::new(Mutex::new(SharedState::new()));
+        let req = TestRequest::with_uri("/ws").to_http_request();
+        let mut principal = Principal::anonymous();
+        principal.rules = parse_rules("allow w feeds/").unwrap();
+        req.extensions_mut().insert(principal);
+
+        // Lines are cut across chunks, and the last one has no line return
+        let chunks = vec![
+            Bytes::from(r#"{"namespace":"feeds/a","val"#),
+            Bytes::from("ue\":\"YQ\"}\n\nnot json\n{\"namespace\":\"other\",\"value\":\"YQ\"}\n"),
+            Bytes::from(r#"{"namespace":"feeds/a","value":"YQ","timestamp":1000}"#),
+        ];
+        let payload = stream::iter_ok::<_, actix_web::error::PayloadError>(chunks);
+        let out: Vec<Bytes> = IngestStream::new(payload, state.clone(), req).wait().map(Result::unwrap).collect();
+        let lines: Vec<serde_json::Value> = out
+            .concat()
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["line"], 3);
+        assert_eq!(lines[0]["status"], "bad_request");
+        assert_eq!(lines[1]["line"], 4);
+        assert_eq!(lines[1]["status"], "forbidden");
+        assert_eq!(lines[2]["lines"], 5);
+        assert_eq!(lines[2]["written"], 2);
+        assert_eq!(lines[2]["failed"], 2);
+        assert_eq!(lines[2]["done"], true);
+        assert_eq!(state.lock().unwrap().db.get_count("feeds/a", "YQ"), 2);
+    }
+
+    #[test]
+    fn test_ingest_long_lines() {
+        let state = Arc::new(Mutex::new(SharedState::new()));
+        let req = TestRequest::with_uri("/ws").to_http_request();
+        req.extensions_mut().insert(Principal::anonymous());
+
+        // Too long within one chunk, then once the rest of the line arrives
+        let long = format!("{}\n", " ".repeat(MAX_LINE + 1));
+        let chunks = vec![
+            Bytes::from(format!("{}{{\"namespace\":\"feeds/a\",\"value\":\"YQ\"}}\n", long)),
+            Bytes::from(" ".repeat(MAX_LINE - 1)),
+            Bytes::from("  \n{\"namespace\":\"feeds/a\",\"value\":\"YQ\"}\n"),
+        ];
+        let payload = stream::iter_ok::<_, actix_web::error::PayloadError>(chunks);
+        let out: Vec<Bytes> = IngestStream::new(payload, state.clone(), req).wait().map(Result::unwrap).collect();
+        let lines: Vec<serde_json::Value> = out
+            .concat()
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["line"], 1);
+        assert_eq!(lines[0]["status"], "payload_too_large");
+        assert_eq!(lines[1]["line"], 3);
+        assert_eq!(lines[1]["status"], "payload_too_large");
+        assert_eq!(lines[2]["lines"], 4);
+        assert_eq!(lines[2]["written"], 2);
+        assert_eq!(state.lock().unwrap().db.get_count("feeds/a", "YQ"), 2);
+    }
+
+    // Some(bytes) is received, None is the client not sending anything yet
+    struct SlowPayload(Vec<Option<Bytes>>);
+
+    impl Stream for SlowPayload {
+        type Item = Bytes;
+        type Error = actix_web::error::PayloadError;
+
+        fn poll(&mut self) -> Poll<Option<Bytes>, Self::Error> {
+            if self.0.is_empty() {
+                return Ok(Async::Ready(None));
+            }
+            match self.0.remove(0) {
+                Some(bytes) => Ok(Async::Ready(Some(bytes))),
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+
+    #[test]
+    fn test_ingest_slow_client() {
+        let state = Arc::new(Mutex::new(SharedState::new()));
+        let req = TestRequest::with_uri("/ws").to_http_request();
+        req.extensions_mut().insert(Principal::anonymous());
+
+        let payload = SlowPayload(vec![
+            Some(Bytes::from("{\"namespace\":\"feeds/a\",\"value\":\"YQ\"}\n{\"namespace\"")),
+            None,
+        ]);
+        let mut ingest = IngestStream::new(payload, state.clone(), req);
+        match ingest.poll().unwrap() {
+            Async::Ready(Some(progress)) => {
+                let progress: serde_json::Value = serde_json::from_slice(&progress).unwrap();
+                assert_eq!(progress["written"], 1);
+                assert_eq!(progress["done"], false);
+            }
+            _ => panic!("The first line should be written"),
+        }
+        assert_eq!(state.lock().unwrap().db.get_count("feeds/a", "YQ"), 1);
+        assert!(ingest.poll().unwrap().is_ready());
+    }
+}
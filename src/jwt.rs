@@ -0,0 +1,256 @@
+use ini::ini::Properties;
+use openssl::bn::BigNum;
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::{Id, PKey, Public};
+use openssl::sign::{Signer, Verifier};
+use serde::Deserialize;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+use crate::acl::{parse_rules, Principal, Rule};
+use crate::ratelimit::Limits;
+
+// JSON Web Tokens checked locally, with no identity provider to call:
+//
+// [jwt]
+// secret=...                 <- HS256, HS384, HS512
+// public_key=ssl/jwt.pem     <- RS256, RS384, RS512 (RSA key) or ES256, ES384, ES512 (EC key)
+// issuer=                    <- when set, the iss claim must match
+// audience=                  <- when set, the aud claim must contain it
+// leeway=60                  <- seconds of clock skew allowed on exp and nbf
+//
+// Tokens need an exp claim, and carry their rules and admin rights in claims:
+// {"sub": "ingest", "exp": 1700000000, "rules": ["allow rw feeds/"], "admin": false}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    #[serde(default)]
+    sub: String,
+    exp: Option<i64>,
+    nbf: Option<i64>,
+    iss: Option<String>,
+    #[serde(default)]
+    aud: Value,
+    #[serde(default)]
+    rules: Vec<String>,
+    #[serde(default)]
+    admin: bool,
+}
+
+pub struct JwtVerifier {
+    secret: Option<Vec<u8>>,
+    public_key: Option<PKey<Public>>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway: i64,
+}
+
+// Three base64url parts, where API keys have none of these dots
+pub fn looks_like_jwt(token: &str) -> bool {
+    token.split('.').count() == 3
+}
+
+fn decode(part: &str) -> Result<Vec<u8>, String> {
+    base64::decode_config(part, base64::URL_SAFE_NO_PAD).map_err(|_| String::from("Invalid token encoding"))
+}
+
+fn digest(bits: &str) -> Option<MessageDigest> {
+    match bits {
+        "256" => Some(MessageDigest::sha256()),
+        "384" => Some(MessageDigest::sha384()),
+        "512" => Some(MessageDigest::sha512()),
+        _ => None,
+    }
+}
+
+// JWT ECDSA signatures are r and s side by side, openssl wants them DER encoded
+fn ecdsa_der(signature: &[u8]) -> Result<Vec<u8>, String> {
+    let error = |_| String::from("Invalid token signature");
+    if signature.is_empty() || !signature.len().is_multiple_of(2) {
+        return Err(String::from("Invalid token signature"));
+    }
+    let (r, s) = signature.split_at(signature.len() / 2);
+    let sig = EcdsaSig::from_private_components(
+        BigNum::from_slice(r).map_err(error)?,
+        BigNum::from_slice(s).map_err(error)?,
+    )
+    .map_err(error)?;
+    sig.to_der().map_err(error)
+}
+
+impl JwtVerifier {
+    pub fn from_config(section: &Properties, config_dir: &Path) -> Result<JwtVerifier, String> {
+        let public_key = match section.get("public_key") {
+            Some(path) => {
+                let path = config_dir.join(path);
+                let pem = fs::read(&path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+                Some(PKey::public_key_from_pem(&pem).map_err(|e| format!("Invalid public key: {}", e))?)
+            }
+            None => None,
+        };
+        let secret = section.get("secret").filter(|s| !s.is_empty()).map(|s| s.as_bytes().to_vec());
+        if secret.is_none() && public_key.is_none() {
+            return Err(String::from("Set a secret or a public_key"));
+        }
+        let leeway = match section.get("leeway") {
+            Some(v) => match v.parse::<i64>() {
+                Ok(leeway) if leeway >= 0 => leeway,
+                _ => return Err(format!("Invalid leeway '{}'", v)),
+            },
+            None => 60,
+        };
+        Ok(JwtVerifier {
+            secret,
+            public_key,
+            issuer: section.get("issuer").filter(|v| !v.is_empty()).cloned(),
+            audience: section.get("audience").filter(|v| !v.is_empty()).cloned(),
+            leeway,
+        })
+    }
+
+    fn check_signature(&self, alg: &str, signed: &[u8], signature: &[u8]) -> Result<(), String> {
+        let unsupported = || format!("Unsupported token algorithm '{}'", alg);
+        let (family, bits) = match (alg.get(..2), alg.get(2..)) {
+            (Some(family), Some(bits)) => (family, bits),
+            _ => return Err(unsupported()),
+        };
+        let md = digest(bits).ok_or_else(unsupported)?;
+        let valid = match family {
+            "HS" => {
+                let secret = self.secret.as_ref().ok_or_else(unsupported)?;
+                let key = PKey::hmac(secret).map_err(|e| e.to_string())?;
+                let mut signer = Signer::new(md, &key).map_err(|e| e.to_string())?;
+                signer.update(signed).map_err(|e| e.to_string())?;
+                let expected = signer.sign_to_vec().map_err(|e| e.to_string())?;
+                expected.len() == signature.len() && memcmp::eq(&expected, signature)
+            }
+            // The key type decides, so a public key can never be used as an HMAC secret
+            "RS" | "ES" => {
+                let key = self.public_key.as_ref().ok_or_else(unsupported)?;
+                let der;
+                let signature = match (family, key.id()) {
+                    ("RS", Id::RSA) => signature,
+                    ("ES", Id::EC) => {
+                        der = ecdsa_der(signature)?;
+                        &der
+                    }
+                    _ => return Err(unsupported()),
+                };
+                let mut verifier = Verifier::new(md, key).map_err(|e| e.to_string())?;
+                verifier.update(signed).map_err(|e| e.to_string())?;
+                verifier.verify(signature).unwrap_or(false)
+            }
+            _ => return Err(unsupported()),
+        };
+        if !valid {
+            return Err(String::from("Invalid token signature"));
+        }
+        Ok(())
+    }
+
+    fn check_claims(&self, claims: &Claims, now: i64) -> Result<(), String> {
+        match claims.exp {
+            Some(exp) if exp.saturating_add(self.leeway) <= now => return Err(String::from("Token expired")),
+            Some(_) => {}
+            None => return Err(String::from("Token without expiry (exp)")),
+        }
+        if claims.nbf.is_some_and(|nbf| nbf.saturating_sub(self.leeway) > now) {
+            return Err(String::from("Token not valid yet"));
+        }
+        if let Some(issuer) = &self.issuer {
+            if claims.iss.as_ref() != Some(issuer) {
+                return Err(String::from("Token from another issuer"));
+            }
+        }
+        if let Some(audience) = &self.audience {
+            let found = match &claims.aud {
+                Value::String(aud) => aud == audience,
+                Value::Array(auds) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+                _ => false,
+            };
+            if !found {
+                return Err(String::from("Token for another audience"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn verify(&self, token: &str, now: i64) -> Result<Principal, String> {
+        let parts: Vec<&str> = token.split('.').collect();
+        if parts.len() != 3 {
+            return Err(String::from("Invalid token"));
+        }
+        let header: Header = serde_json::from_slice(&decode(parts[0])?).map_err(|_| String::from("Invalid token header"))?;
+        let signed_len = parts[0].len() + 1 + parts[1].len();
+        self.check_signature(&header.alg, &token.as_bytes()[..signed_len], &decode(parts[2])?)?;
+
+        let claims: Claims = serde_json::from_slice(&decode(parts[1])?).map_err(|_| String::from("Invalid token claims"))?;
+        self.check_claims(&claims, now)?;
+        let rules: Vec<Rule> = claims
+            .rules
+            .iter()
+            .map(|rules| parse_rules(rules))
+            .collect::<Result<Vec<_>, _>>()?
+            .concat();
+        Ok(Principal {
+            id: format!("jwt:{}", claims.sub),
+            rules,
+            admin: claims.admin,
+            limits: Limits::default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ini::Ini;
+
+    fn hs256(secret: &str, claims: &str) -> String {
+        let encode = |part: &str| base64::encode_config(part, base64::URL_SAFE_NO_PAD);
+        let signed = format!("{}.{}", encode(r#"{"alg":"HS256","typ":"JWT"}"#), encode(claims));
+        let key = PKey::hmac(secret.as_bytes()).unwrap();
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+        signer.update(signed.as_bytes()).unwrap();
+        let signature = base64::encode_config(&signer.sign_to_vec().unwrap(), base64::URL_SAFE_NO_PAD);
+        format!("{}.{}", signed, signature)
+    }
+
+    #[test]
+    fn test_verify_hs256() {
+        let config = Ini::load_from_str("[jwt]\nsecret=s3cret\nleeway=0\n").unwrap();
+        let verifier = JwtVerifier::from_config(config.section(Some("jwt")).unwrap(), Path::new(".")).unwrap();
+
+        let token = hs256("s3cret", r#"{"sub":"ingest","exp":2000,"rules":["allow rw feeds/"]}"#);
+        assert!(looks_like_jwt(&token));
+        let principal = verifier.verify(&token, 1000).unwrap();
+        assert_eq!(principal.id, "jwt:ingest");
+        assert_eq!(principal.rules[0].to_string(), "allow rw feeds/");
+        assert!(!principal.admin);
+
+        assert_eq!(verifier.verify(&token, 2000).unwrap_err(), "Token expired");
+        let forged = hs256("guess", r#"{"sub":"ingest","exp":2000,"admin":true}"#);
+        assert!(verifier.verify(&forged, 1000).is_err());
+        let no_exp = hs256("s3cret", r#"{"sub":"ingest"}"#);
+        assert!(verifier.verify(&no_exp, 1000).is_err());
+
+        // Extreme claims do not wrap around the leeway
+        let config = Ini::load_from_str("[jwt]\nsecret=s3cret\nleeway=60\n").unwrap();
+        let verifier = JwtVerifier::from_config(config.section(Some("jwt")).unwrap(), Path::new(".")).unwrap();
+        let extreme = hs256(
+            "s3cret",
+            &format!(r#"{{"sub":"ingest","exp":{},"nbf":{}}}"#, i64::MAX, i64::MIN),
+        );
+        assert!(verifier.verify(&extreme, 1000).is_ok());
+        let config = Ini::load_from_str("[jwt]\nsecret=s3cret\nleeway=-60\n").unwrap();
+        assert!(JwtVerifier::from_config(config.section(Some("jwt")).unwrap(), Path::new(".")).is_err());
+    }
+}
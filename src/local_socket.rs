@@ -0,0 +1,98 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+use crate::acl::{Principal, Rule};
+use crate::ratelimit::Limits;
+
+// Unix domain socket listener for writers on the same host, serving the same routes without TLS:
+//
+// [daemon]
+// unix_socket=/run/sightingdb/sightingdb.sock
+// unix_socket_mode=0660          <- who may connect is up to the socket owner, group and mode
+// unix_socket_rules=allow rw *   <- rules of requests sent over the socket without an API key
+//
+// Stored in the extensions of every request made over the socket.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LocalConnection;
+
+// An octal mode, like chmod
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+    let octal = mode.trim_start_matches("0o");
+    match u32::from_str_radix(octal, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("Invalid unix_socket_mode '{}'", mode)),
+    }
+}
+
+// The socket never exists with wider permissions than asked for: it is created with every
+// permission masked, then given its mode. A socket left by a previous run is replaced, one a
+// running daemon still listens on and anything else at the path are left alone and refused.
+pub fn bind(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => match UnixStream::connect(path) {
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is in use by another process", path.display()),
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+            Err(e) => return Err(e),
+        },
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let umask = unsafe { libc::umask(0o777) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    let listener = listener?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+pub fn principal(rules: &[Rule]) -> Principal {
+    Principal {
+        id: String::from("unix"),
+        rules: rules.to_vec(),
+        admin: false,
+        limits: Limits::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind_mode() {
+        assert_eq!(parse_mode("0660"), Ok(0o660));
+        assert_eq!(parse_mode("600"), Ok(0o600));
+        assert!(parse_mode("0999").is_err());
+
+        let path = std::env::temp_dir().join(format!("sightingdb-test-{}.sock", std::process::id()));
+        let listener = bind(&path, 0o644).unwrap();
+        // A daemon still listens on it
+        assert_eq!(bind(&path, 0o600).unwrap_err().kind(), io::ErrorKind::AddrInUse);
+        // Left by a previous run
+        drop(listener);
+        let _listener = bind(&path, 0o600).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        fs::remove_file(&path).unwrap();
+
+        // A regular file is not replaced
+        fs::write(&path, "data").unwrap();
+        assert_eq!(bind(&path, 0o600).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "data");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -5,12 +5,27 @@ extern crate dirs;
 extern crate qstring;
 
 mod acl;
+mod anomaly;
+mod apikeys;
+mod auth;
+mod client_cert;
 mod attribute;
+mod audit;
 mod db;
+mod error;
+mod export;
+mod namespace;
 mod sighting_configure;
 mod sighting_reader;
 mod sighting_writer;
 mod db_log;
+mod jwt;
+mod local_socket;
+mod ratelimit;
+mod series;
+mod heavy_hitters;
+mod ingest;
+mod time_index;
 
 use clap::Arg;
 use std::sync::Arc;
@@ -20,13 +35,30 @@ use ansi_term::Color::Red;
 use daemonize::Daemonize;
 use ini::Ini;
 
-use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
-use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use actix_http::HttpService;
+use actix_server::ssl::{OpensslAcceptor, SslError};
+use actix_service::NewService;
+use actix_web::body::Body;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::{web, App, HttpMessage, HttpRequest, HttpResponse, Responder};
+use futures::future::{ok, Either};
+use futures::Future;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
+use openssl::x509::X509Name;
+use tokio_openssl::SslStream;
+use tokio_tcp::TcpStream;
+use tokio_uds::UnixStream;
 
 use qstring::QString;
 
 use serde::{Deserialize, Serialize};
 
+use chrono::Utc;
+use heavy_hitters::TopBy;
+use namespace::SortBy;
+use series::SeriesQuery;
+use time_index::TimeFilter;
+
 use std::fs;
 use std::fs::File;
 use std::path::{Path, PathBuf};
@@ -34,6 +66,12 @@ use std::path::{Path, PathBuf};
 pub struct SharedState {
     pub db: db::Database,
     pub authenticate: bool,
+    pub certificates: client_cert::CertificateRules,
+    pub jwt: Option<jwt::JwtVerifier>,
+    pub audit: audit::AuditLog,
+    pub limits: ratelimit::RateLimiter,
+    // Rules of requests made over the unix socket without credentials
+    pub local_rules: Option<Vec<acl::Rule>>,
 }
 
 impl SharedState {
@@ -41,6 +79,11 @@ impl SharedState {
         Self {
             db: db::Database::new(),
             authenticate: true,
+            certificates: client_cert::CertificateRules::new(),
+            jwt: None,
+            audit: audit::AuditLog::new(),
+            limits: ratelimit::RateLimiter::new(),
+            local_rules: None,
         }
     }
 }
@@ -69,58 +112,59 @@ fn help(_req: HttpRequest) -> impl Responder {
 REST Endpoints:
 \t/w: write (GET)
 \t/wb: write in bulk mode (POST)
+\t/ws: write in bulk from a stream of JSON lines (POST)
 \t/r: read (GET)
 \t/rs: read with statistics (GET)
 \t/rb: read in bulk mode (POST)
 \t/rbs: read with statistics in bulk mode (POST)
 \t/d: delete (GET)
+\t/ns: namespace statistics (GET)
+\t/anomalies: values of a namespace currently spiking (GET)
+\t/top: most sighted values, globally or per namespace (GET)
+\t/export: stream the namespaces under a prefix as NDJSON or CSV (GET)
+\t/audit: recent audit events (GET), admin only
+\t/k: list (GET) and create (POST) API keys, admin only
+\t/k/<id>/rotate: new secret for an API key (POST), admin only
+\t/k/<id>: revoke an API key (DELETE), admin only
 \t/c: configure (GET)
 \t/i: info (GET)
 "
 }
 
+// Reads are serialized by sighting_reader, or fail with their error status
+fn json_body(ans: Result<String, error::Error>) -> HttpResponse {
+    match ans {
+        Ok(body) => HttpResponse::Ok().content_type("application/json").body(body),
+        Err(e) => e.response(),
+    }
+}
+
 fn read_with_stats(data: web::Data<Arc<Mutex<SharedState>>>, _req: HttpRequest) -> impl Responder {
     let sharedstate = &mut *data.lock().unwrap();
 
     let (_, path) = _req.path().split_at(4);
-    if sharedstate.authenticate {
-        let http_header_auth = _req.head().headers.get("Authorization");
-        match http_header_auth {
-            Some(apikey) => {
-                let can_read = acl::can_read(&mut sharedstate.db, apikey.to_str().unwrap(), path);
-                if !can_read {
-                    return HttpResponse::Ok().json(Message {
-                        message: String::from("API key not found."),
-                    });
-                }
-            }
-            None => {
-                return HttpResponse::Ok().json(Message {
-                    message: String::from("Please add the API key in the Authorization headers."),
-                });
-            }
-        }
+    if let Err(denied) = auth::authorize(&_req, acl::Permission::Read, path) {
+        return denied;
     }
     let query_string = QString::from(_req.query_string());
 
-    let val = query_string.get("noshadow");
-    let mut with_shadow = true;
-    match val {
-        Some(_v) => {
-            with_shadow = false;
-        }
-        None => {}
-    }
+    let with_shadow = query_string.get("noshadow").is_none();
+
+    let series = match SeriesQuery::from_query(&query_string, Utc::now().timestamp()) {
+        Ok(series) => series,
+        Err(message) => return error::Error::BadRequest(message).response(),
+    };
 
     let val = query_string.get("val");
     match val {
         Some(v) => {
-            let ans = sighting_reader::read(&mut sharedstate.db, path, v, true, with_shadow);
-            HttpResponse::Ok().body(ans)
+            let ans = match series {
+                Some(query) => sighting_reader::read_series(&mut sharedstate.db, path, v, &query, with_shadow),
+                None => sighting_reader::read(&mut sharedstate.db, path, v, true, with_shadow),
+            };
+            json_body(ans)
         }
-        None => HttpResponse::Ok().json(Message {
-            message: String::from("Error: val= not found!"),
-        }),
+        None => error::Error::BadRequest(String::from("Error: val= not found!")).response(),
     }
 }
 
@@ -128,100 +172,139 @@ fn read(data: web::Data<Arc<Mutex<SharedState>>>, _req: HttpRequest) -> impl Res
     let sharedstate = &mut *data.lock().unwrap();
 
     let (_, path) = _req.path().split_at(3);
-    if sharedstate.authenticate {
-        let http_header_auth = _req.head().headers.get("Authorization");
-        match http_header_auth {
-            Some(apikey) => {
-                let can_read = acl::can_read(&mut sharedstate.db, apikey.to_str().unwrap(), path);
-                if !can_read {
-                    return HttpResponse::Ok().json(Message {
-                        message: String::from("API key not found."),
-                    });
-                }
-            }
-            None => {
-                return HttpResponse::Ok().json(Message {
-                    message: String::from("Please add the API key in the Authorization headers."),
-                });
-            }
-        }
+    if let Err(denied) = auth::authorize(&_req, acl::Permission::Read, path) {
+        return denied;
     }
 
     let query_string = QString::from(_req.query_string());
 
-    let val = query_string.get("noshadow");
-    let mut with_shadow = true;
-    match val {
-        Some(_v) => {
-            with_shadow = false;
-        }
-        None => {}
-    }
+    let with_shadow = query_string.get("noshadow").is_none();
 
 
     let val = query_string.get("val");
     match val {
         Some(v) => {
             let ans = sighting_reader::read(&mut sharedstate.db, path, v, false, with_shadow);
-            HttpResponse::Ok().body(ans)
+            json_body(ans)
         }
         // None => HttpResponse::Ok().json(Message {
         //     message: String::from("Error: val= not found!"),
         // }),
         None => {
-            let ans = sighting_reader::read_namespace(&mut sharedstate.db, path);
-            HttpResponse::Ok().body(ans)
+            let filter = match TimeFilter::from_query(&query_string) {
+                Ok(filter) => filter,
+                Err(e) => return e.response(),
+            };
+            let sort = match query_string.get("sort") {
+                Some(name) => match SortBy::from_name(name) {
+                    Some(sort) => Some(sort),
+                    None => {
+                        return error::Error::BadRequest(String::from(
+                            "sort= must be score, count, first_seen or last_seen.",
+                        ))
+                        .response();
+                    }
+                },
+                None => None,
+            };
+            let ans = sighting_reader::read_namespace(&mut sharedstate.db, path, &filter, sort);
+            json_body(ans)
         }
     }
 }
 
+fn namespace_stats(data: web::Data<Arc<Mutex<SharedState>>>, _req: HttpRequest) -> HttpResponse {
+    let sharedstate = &mut *data.lock().unwrap();
+
+    let (_, path) = _req.path().split_at(4); // We remove '/ns/'
+    if let Err(denied) = auth::authorize(&_req, acl::Permission::Read, path) {
+        return denied;
+    }
+
+    let ans = sighting_reader::read_namespace_stats(&mut sharedstate.db, path);
+    json_body(ans)
+}
+
+fn anomalies(data: web::Data<Arc<Mutex<SharedState>>>, _req: HttpRequest) -> HttpResponse {
+    let sharedstate = &mut *data.lock().unwrap();
+
+    let (_, path) = _req.path().split_at(11); // We remove '/anomalies/'
+    if let Err(denied) = auth::authorize(&_req, acl::Permission::Read, path) {
+        return denied;
+    }
+
+    let ans = sighting_reader::read_anomalies(&mut sharedstate.db, path);
+    json_body(ans)
+}
+
+fn top(data: web::Data<Arc<Mutex<SharedState>>>, _req: HttpRequest) -> HttpResponse {
+    let sharedstate = &mut *data.lock().unwrap();
+
+    let (_, path) = _req.path().split_at(4); // We remove '/top'
+    let path = path.trim_start_matches('/');
+    let authorized = if path.is_empty() {
+        auth::authorize_all(&_req, acl::Permission::Read)
+    } else {
+        auth::authorize(&_req, acl::Permission::Read, path)
+    };
+    if let Err(denied) = authorized {
+        return denied;
+    }
+
+    let query_string = QString::from(_req.query_string());
+
+    let n = query_string.get("n").and_then(|n| n.parse::<usize>().ok()).unwrap_or(100);
+    let by = match query_string.get("by").unwrap_or("count") {
+        "count" => TopBy::Count,
+        "recent_count" => TopBy::RecentCount {
+            hours: query_string
+                .get("hours")
+                .and_then(|h| h.parse::<i64>().ok())
+                .unwrap_or(24),
+        },
+        _ => {
+            return error::Error::BadRequest(String::from("by= must be either count or recent_count.")).response();
+        }
+    };
+
+    let namespace = if path.is_empty() { None } else { Some(path) };
+    let ans = sighting_reader::read_top(&mut sharedstate.db, namespace, n, by);
+    json_body(ans)
+}
+
 // fn write(db: web::Data<Mutex<db::Database>>, _req: HttpRequest) -> impl Responder {
 fn write(data: web::Data<Arc<Mutex<SharedState>>>, _req: HttpRequest) -> HttpResponse {
     let sharedstate = &mut *data.lock().unwrap();
 
     // println!("{:?}", _req.path());
     let (_, path) = _req.path().split_at(3); // We remove '/w/'
-
-    if sharedstate.authenticate {
-        let http_header_auth = _req.head().headers.get("Authorization");
-        match http_header_auth {
-            Some(apikey) => {
-                let can_write = acl::can_write(&mut sharedstate.db, apikey.to_str().unwrap(), path);
-                if !can_write {
-                    let mut error_msg = String::from("Cannot write to namespace: /");
-                    error_msg.push_str(path);
-                    return HttpResponse::Ok().json(Message { message: error_msg });
-                }
-            }
-            None => {
-                return HttpResponse::Ok().json(Message {
-                    message: String::from("Please add the API key in the Authorization headers."),
-                });
-            }
-        }
-    }
+    let principal = match auth::authorize(&_req, acl::Permission::Write, path) {
+        Ok(principal) => principal,
+        Err(denied) => return denied,
+    };
 
     let query_string = QString::from(_req.query_string());
 
     let val = query_string.get("val");
     match val {
         Some(v) => {
-            let timestamp = query_string.get("timestamp").unwrap_or("0");
-            let timestamp_i = timestamp.parse::<i64>().unwrap_or(0);
-            let could_write = sighting_writer::write(&mut sharedstate.db, path, v, timestamp_i);
-            if could_write {
-                HttpResponse::Ok().json(Message {
+            let new_values = (sharedstate.db.get_count(path, v) == 0) as u64;
+            let now = Utc::now().timestamp();
+            if let Err(retry_after) = sharedstate.limits.check_quota(&principal, 1, new_values, now) {
+                return ratelimit::too_many_requests("Daily write quota exceeded.", retry_after).response();
+            }
+            let timestamp_i = query_string
+                .get("timestamp")
+                .and_then(|timestamp| timestamp.parse::<i64>().ok())
+                .unwrap_or(attribute::NOW);
+            match sighting_writer::write(&mut sharedstate.db, path, v, timestamp_i) {
+                Ok(_) => HttpResponse::Ok().json(Message {
                     message: String::from("ok"),
-                })
-            } else {
-                HttpResponse::Ok().json(Message {
-                    message: String::from("Could not write request!"),
-                })
+                }),
+                Err(e) => e.response(),
             }
         }
-        None => HttpResponse::BadRequest().json(Message {
-            message: String::from("Did not received a val= argument in the query string."),
-        }),
+        None => error::Error::BadRequest(String::from("Did not received a val= argument in the query string.")).response(),
     }
 }
 
@@ -229,17 +312,27 @@ fn configure(_req: HttpRequest) -> impl Responder {
     "configure"
 }
 
-#[derive(Serialize, Deserialize)]
+// Only bulk reads use the time window: items outside of it are left out. Its bounds are
+// listed rather than a flattened TimeFilter, serde_json cannot read numbers back from
+// flattened structs with arbitrary_precision.
+#[derive(Deserialize)]
 pub struct PostData {
-    items: Vec<BulkSighting>,
+    items: Vec<sighting_writer::BulkSighting>,
+    first_seen_after: Option<i64>,
+    first_seen_before: Option<i64>,
+    last_seen_after: Option<i64>,
+    last_seen_before: Option<i64>,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct BulkSighting {
-    namespace: String,
-    value: String,
-    timestamp: Option<i64>,
-    noshadow: bool,
+impl PostData {
+    fn filter(&self) -> TimeFilter {
+        TimeFilter {
+            first_seen_after: self.first_seen_after,
+            first_seen_before: self.first_seen_before,
+            last_seen_after: self.last_seen_after,
+            last_seen_before: self.last_seen_before,
+        }
+    }
 }
 
 fn read_bulk(
@@ -247,54 +340,7 @@ fn read_bulk(
     postdata: web::Json<PostData>,
     _req: HttpRequest,
 ) -> impl Responder {
-    let sharedstate = &mut *data.lock().unwrap();
-
-    let mut json_response = String::from("{\n\t\"items\": [\n");
-
-    for v in &postdata.items {
-        if sharedstate.authenticate {
-            let http_header_auth = _req.head().headers.get("Authorization");
-            match http_header_auth {
-                Some(apikey) => {
-                    let can_read = acl::can_read(
-                        &mut sharedstate.db,
-                        apikey.to_str().unwrap(),
-                        v.namespace.as_str(),
-                    );
-                    if !can_read {
-                        return HttpResponse::Ok().json(Message {
-                            message: String::from("API key not found."),
-                        });
-                    }
-                }
-                None => {
-                    return HttpResponse::Ok().json(Message {
-                        message: String::from(
-                            "Please add the API key in the Authorization headers.",
-                        ),
-                    });
-                }
-            }
-        }
-
-        let ans = sighting_reader::read(
-            &mut sharedstate.db,
-            v.namespace.as_str(),
-            v.value.as_str(),
-            false, // no stats
-            !v.noshadow,
-        );
-
-        json_response.push_str("\t\t");
-        json_response.push_str(&ans);
-        json_response.push_str(",\n");
-    }
-    json_response.pop();
-    json_response.pop(); // We don't need the last ,
-    json_response.push_str("\n"); // however we need the line return :)
-
-    json_response.push_str("\t]\n}\n");
-    HttpResponse::Ok().body(json_response)
+    read_bulk_items(data, postdata, _req, false)
 }
 
 fn read_bulk_with_stats(
@@ -302,138 +348,259 @@ fn read_bulk_with_stats(
     postdata: web::Json<PostData>,
     _req: HttpRequest,
 ) -> impl Responder {
-    let sharedstate = &mut *data.lock().unwrap();
+    read_bulk_items(data, postdata, _req, true)
+}
 
-    let mut json_response = String::from("{\n\t\"items\": [\n");
+fn read_bulk_items(
+    data: web::Data<Arc<Mutex<SharedState>>>,
+    postdata: web::Json<PostData>,
+    _req: HttpRequest,
+    with_stats: bool,
+) -> HttpResponse {
+    let sharedstate = &mut *data.lock().unwrap();
+    _req.extensions_mut().insert(audit::AuditItems(
+        postdata
+            .items
+            .iter()
+            .map(|v| (v.namespace.clone(), v.value.clone()))
+            .collect(),
+    ));
+
+    if let Err(denied) = auth::principal(&_req) {
+        return denied;
+    }
 
+    let filter = postdata.filter();
+    let mut items = Vec::new();
     for v in &postdata.items {
-        if sharedstate.authenticate {
-            let http_header_auth = _req.head().headers.get("Authorization");
-            match http_header_auth {
-                Some(apikey) => {
-                    let can_read = acl::can_read(
-                        &mut sharedstate.db,
-                        apikey.to_str().unwrap(),
-                        v.namespace.as_str(),
-                    );
-                    if !can_read {
-                        return HttpResponse::Ok().json(Message {
-                            message: String::from("API key not found."),
-                        });
-                    }
-                }
-                None => {
-                    return HttpResponse::Ok().json(Message {
-                        message: String::from(
-                            "Please add the API key in the Authorization headers.",
-                        ),
-                    });
-                }
-            }
+        if let Err(e) = auth::check(&_req, acl::Permission::Read, &v.namespace) {
+            items.push(sighting_reader::BulkReadItem::failed(&v.namespace, &v.value, &e));
+            continue;
+        }
+
+        // Values that do not exist are reported as such by read_item
+        let outside = sharedstate
+            .db
+            .attribute(&v.namespace, &v.value)
+            .is_some_and(|attr| !filter.matches(attr));
+        if outside {
+            items.push(sighting_reader::BulkReadItem::filtered_out(&v.namespace, &v.value));
+            continue;
         }
 
-        let ans = sighting_reader::read(
+        items.push(sighting_reader::read_item(
             &mut sharedstate.db,
             v.namespace.as_str(),
             v.value.as_str(),
-            true,
+            with_stats,
             !v.noshadow,
-        );
-
-        json_response.push_str("\t\t");
-        json_response.push_str(&ans);
-        json_response.push_str(",\n");
+        ));
     }
-    json_response.pop();
-    json_response.pop(); // We don't need the last ,
-    json_response.push_str("\n"); // however we need the line return :)
 
-    json_response.push_str("\t]\n}\n");
-    HttpResponse::Ok().body(json_response)
+    HttpResponse::Ok().json(sighting_reader::BulkReadResponse { items })
+}
+
+#[derive(Deserialize)]
+pub struct WriteBulkData {
+    items: Vec<sighting_writer::BulkSighting>,
+    #[serde(default)]
+    mode: sighting_writer::WriteMode,
 }
 
 fn write_bulk(
     data: web::Data<Arc<Mutex<SharedState>>>,
-    postdata: web::Json<PostData>,
+    postdata: web::Json<WriteBulkData>,
     _req: HttpRequest,
 ) -> impl Responder {
     let sharedstate = &mut *data.lock().unwrap();
-    let mut could_write = false;
+    _req.extensions_mut().insert(audit::AuditItems(
+        postdata
+            .items
+            .iter()
+            .map(|v| (v.namespace.clone(), v.value.clone()))
+            .collect(),
+    ));
+
+    let principal = match auth::principal(&_req) {
+        Ok(principal) => principal,
+        Err(denied) => return denied,
+    };
 
-    for v in &postdata.items {
-        if !v.value.is_empty() {
-            // There is no need to write a value that does not exists
-            let http_header_auth = _req.head().headers.get("Authorization");
-            match http_header_auth {
-                Some(apikey) => {
-                    let can_write = acl::can_write(
-                        &mut sharedstate.db,
-                        apikey.to_str().unwrap(),
-                        v.namespace.as_str(),
-                    );
-                    if !can_write {
-                        return HttpResponse::Ok().json(Message {
-                            message: String::from("API key not found."),
-                        });
-                    }
-                }
-                None => {
-                    return HttpResponse::Ok().json(Message {
-                        message: String::from(
-                            "Please add the API key in the Authorization headers.",
-                        ),
-                    });
-                }
-            }
+    let SharedState { db, limits, .. } = sharedstate;
+    let result = sighting_writer::write_bulk(
+        db,
+        &postdata.items,
+        postdata.mode,
+        |namespace| auth::check(&_req, acl::Permission::Write, namespace).map(|_| ()),
+        |writes, new_values| {
+            limits
+                .check_quota(&principal, writes, new_values, Utc::now().timestamp())
+                .map_err(|retry_after| ratelimit::too_many_requests("Daily write quota exceeded.", retry_after))
+        },
+    );
+    match result {
+        Ok((status, response)) => HttpResponse::build(status).json(response),
+        Err(e) => e.response(),
+    }
+}
 
-            let timestamp = v.timestamp.unwrap_or(0);
-            could_write = sighting_writer::write(
-                &mut sharedstate.db,
-                v.namespace.as_str(),
-                v.value.as_str(),
-                timestamp,
-            );
-        }
+// Streamed bulk write of newline-delimited JSON sightings, see ingest
+fn write_stream(
+    data: web::Data<Arc<Mutex<SharedState>>>,
+    payload: web::Payload,
+    _req: HttpRequest,
+) -> HttpResponse {
+    if let Err(denied) = auth::principal(&_req) {
+        return denied;
     }
 
-    if could_write {
-        return HttpResponse::Ok().json(Message {
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(ingest::IngestStream::new(payload, data.get_ref().clone(), _req))
+}
+
+// Streamed export of the namespaces under a prefix, see export
+fn export(data: web::Data<Arc<Mutex<SharedState>>>, _req: HttpRequest) -> HttpResponse {
+    let principal = match auth::principal(&_req) {
+        Ok(principal) => principal,
+        Err(denied) => return denied,
+    };
+
+    let (_, prefix) = _req.path().split_at(7); // We remove '/export'
+    let prefix = prefix.trim_start_matches('/');
+    let query_string = QString::from(_req.query_string());
+    let options = match export::ExportOptions::from_query(&query_string) {
+        Ok(options) => options,
+        Err(e) => return e.response(),
+    };
+
+    HttpResponse::Ok()
+        .content_type(options.format.content_type())
+        .streaming(export::ExportStream::new(data.get_ref().clone(), &principal, prefix, options))
+}
+
+fn delete(data: web::Data<Arc<Mutex<SharedState>>>, _req: HttpRequest) -> HttpResponse {
+    let sharedstate = &mut *data.lock().unwrap();
+
+    let (_, path) = _req.path().split_at(3); // We remove '/d/'
+    if let Err(denied) = auth::authorize(&_req, acl::Permission::Delete, path) {
+        return denied;
+    }
+
+    match sharedstate.db.delete(path) {
+        Ok(()) => HttpResponse::Ok().json(Message {
             message: String::from("ok"),
-        });
+        }),
+        Err(e) => e.response(),
     }
-    HttpResponse::Ok().json(Message {
-        message: String::from("Invalid base64 encoding (base64 url with non padding) value"),
+}
+
+#[derive(Serialize)]
+pub struct AuditResponse<'a> {
+    events: Vec<&'a audit::AuditEvent>,
+}
+
+fn audit_log(data: web::Data<Arc<Mutex<SharedState>>>, _req: HttpRequest) -> HttpResponse {
+    let sharedstate = &mut *data.lock().unwrap();
+    if let Err(denied) = auth::require_admin(&_req) {
+        return denied;
+    }
+
+    let query_string = QString::from(_req.query_string());
+    let query = audit::AuditQuery {
+        principal: query_string.get("principal").map(str::to_string),
+        namespace: query_string.get("namespace").map(str::to_string),
+        outcome: query_string.get("outcome").map(str::to_string),
+        since: query_string.get("since").and_then(|s| s.parse::<i64>().ok()),
+    };
+    let n = query_string.get("n").and_then(|n| n.parse::<usize>().ok()).unwrap_or(100);
+    HttpResponse::Ok().json(AuditResponse {
+        events: sharedstate.audit.query(&query, n),
     })
 }
 
-fn delete(data: web::Data<Arc<Mutex<SharedState>>>, _req: HttpRequest) -> HttpResponse {
+#[derive(Serialize)]
+pub struct KeysResponse<'a> {
+    keys: Vec<&'a apikeys::ApiKey>,
+}
+
+// The secret is only ever returned here, when the key is created or rotated
+#[derive(Serialize)]
+pub struct KeySecretResponse {
+    key: String,
+    #[serde(flatten)]
+    apikey: apikeys::ApiKey,
+}
+
+#[derive(Deserialize)]
+pub struct NewKey {
+    #[serde(default)]
+    description: String,
+    rules: Vec<acl::Rule>,
+    #[serde(default)]
+    admin: bool,
+    #[serde(default)]
+    limits: ratelimit::Limits,
+}
+
+fn list_keys(data: web::Data<Arc<Mutex<SharedState>>>, _req: HttpRequest) -> HttpResponse {
     let sharedstate = &mut *data.lock().unwrap();
+    if let Err(denied) = auth::require_admin(&_req) {
+        return denied;
+    }
 
-    let (_, path) = _req.path().split_at(3); // We remove '/w/'
-    let http_header_auth = _req.head().headers.get("Authorization");
-    match http_header_auth {
-        Some(apikey) => {
-            let can_write = acl::can_write(&mut sharedstate.db, apikey.to_str().unwrap(), path);
-            if !can_write {
-                let mut error_msg = String::from("Cannot write to namespace: /");
-                error_msg.push_str(path);
-                return HttpResponse::Ok().json(Message { message: error_msg });
-            }
-        }
-        None => {
-            return HttpResponse::Ok().json(Message {
-                message: String::from("Please add the API key in the Authorization headers."),
-            });
-        }
+    HttpResponse::Ok().json(KeysResponse {
+        keys: sharedstate.db.apikeys.list(),
+    })
+}
+
+fn create_key(
+    data: web::Data<Arc<Mutex<SharedState>>>,
+    postdata: web::Json<NewKey>,
+    _req: HttpRequest,
+) -> HttpResponse {
+    let sharedstate = &mut *data.lock().unwrap();
+    if let Err(denied) = auth::require_admin(&_req) {
+        return denied;
     }
 
-    let deleted = sharedstate.db.delete(path);
-    if !deleted {
-        return HttpResponse::Ok().json(Message {
-            message: String::from("Namespace not found, nothing was deleted."),
-        });
+    let newkey = postdata.into_inner();
+    let (apikey, key) = sharedstate
+        .db
+        .apikeys
+        .create(&newkey.description, newkey.rules, newkey.admin, newkey.limits);
+    HttpResponse::Ok().json(KeySecretResponse { key, apikey })
+}
+
+fn rotate_key(
+    data: web::Data<Arc<Mutex<SharedState>>>,
+    id: web::Path<String>,
+    _req: HttpRequest,
+) -> HttpResponse {
+    let sharedstate = &mut *data.lock().unwrap();
+    if let Err(denied) = auth::require_admin(&_req) {
+        return denied;
     }
 
+    match sharedstate.db.apikeys.rotate(&id) {
+        Some((apikey, key)) => HttpResponse::Ok().json(KeySecretResponse { key, apikey }),
+        None => error::Error::NotFound(String::from("API key not found.")).response(),
+    }
+}
+
+fn revoke_key(
+    data: web::Data<Arc<Mutex<SharedState>>>,
+    id: web::Path<String>,
+    _req: HttpRequest,
+) -> HttpResponse {
+    let sharedstate = &mut *data.lock().unwrap();
+    if let Err(denied) = auth::require_admin(&_req) {
+        return denied;
+    }
+
+    if !sharedstate.db.apikeys.revoke(&id) {
+        return error::Error::NotFound(String::from("API key not found.")).response();
+    }
     HttpResponse::Ok().json(Message {
         message: String::from("ok"),
     })
@@ -492,6 +659,56 @@ fn sightingdb_get_pid() -> String {
     // return String::from("./sightingdb.pid"); This is the default, but since the compiler gives a warning, I comment this out
 }
 
+// --export: the daemon of the configuration file is asked for the export, on the same host
+fn export_client(matches: &clap::ArgMatches, daemon_config: &ini::ini::Properties, prefix: &str) -> i32 {
+    let port = match daemon_config.get("listen_port").filter(|port| !port.is_empty()) {
+        Some(port) => port,
+        None => {
+            eprintln!("Cannot export: the daemon has no listen_port");
+            return 1;
+        }
+    };
+    let host = match daemon_config.get("listen_ip").map(String::as_str) {
+        None | Some("0.0.0.0") => "127.0.0.1",
+        Some("::") => "[::1]",
+        Some(ip) => ip,
+    };
+    let scheme = match daemon_config.get("ssl").map(String::as_str) {
+        Some("false") => "http",
+        _ => "https",
+    };
+
+    let mut query = vec![("format", matches.value_of("export-format").unwrap())];
+    if matches.is_present("export-stats") {
+        query.push(("stats", "true"));
+    }
+    if matches.is_present("export-tags") {
+        query.push(("tags", "true"));
+    }
+    if let Some(after) = matches.value_of("last-seen-after") {
+        query.push(("last_seen_after", after));
+    }
+    if let Some(before) = matches.value_of("last-seen-before") {
+        query.push(("last_seen_before", before));
+    }
+    let url = format!(
+        "{}://{}:{}/export/{}?{}",
+        scheme,
+        host,
+        port,
+        prefix.trim_start_matches('/'),
+        QString::new(query)
+    );
+
+    match export::run_client(&url, matches.value_of("apikey"), matches.is_present("insecure")) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Cannot export: {}", e);
+            1
+        }
+    }
+}
+
 fn main() {
     create_home_config();
 
@@ -530,10 +747,42 @@ fn main() {
                 .help("Set the default API KEY")
                 .takes_value(true)
         )
+        .arg(
+            Arg::with_name("export")
+                .long("export")
+                .value_name("PREFIX")
+                .help("Writes the namespaces under PREFIX, read from the running daemon with the -k key, to the standard output")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("export-format")
+                .long("export-format")
+                .value_name("FORMAT")
+                .possible_values(&["ndjson", "csv"])
+                .default_value("ndjson")
+                .takes_value(true)
+        )
+        .arg(Arg::with_name("export-stats").long("export-stats").help("Exports the statistics of each value"))
+        .arg(Arg::with_name("export-tags").long("export-tags").help("Exports the tags of each value"))
+        .arg(
+            Arg::with_name("last-seen-after")
+                .long("last-seen-after")
+                .value_name("TIMESTAMP")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("last-seen-before")
+                .long("last-seen-before")
+                .value_name("TIMESTAMP")
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("insecure")
+                .long("insecure")
+                .help("Accepts the self-signed certificate of the daemon when exporting")
+        )
         .get_matches();
 
-    log4rs::init_file(matches.value_of("logging_config").unwrap_or("etc/log4rs.yml"), Default::default()).unwrap();
-
     // match matches.occurrences_of("v") {
     //     0 => println!("No verbose info"),
     //     1 => println!("Some verbose info"),
@@ -542,49 +791,34 @@ fn main() {
     // }
 
     let configarg = matches.value_of("config");
-    let configstr;
-    match configarg {
-        Some(_configstr) => {
-            configstr = _configstr.to_string();
-        }
-        None => {
-            let sightingdb_ini_file = sightingdb_get_config().unwrap();
-            configstr = sightingdb_ini_file;
-        }
-    }
+    let configstr = match configarg {
+        Some(_configstr) => _configstr.to_string(),
+        None => sightingdb_get_config().unwrap(),
+    };
 
-    let apikeyarg = matches.value_of("apikey");
-    match apikeyarg {
-        Some(apikey) => {
-            sharedstate.lock().unwrap().db.delete("_config/acl/apikeys/changeme");
-            let mut namespace_withkey = String::from("_config/acl/apikeys/");
-            namespace_withkey.push_str(apikey);
-            sharedstate.lock().unwrap().db.write(&namespace_withkey, "", 0, false);
-        }
-        None => {}
-    }
+    let configpath = Path::new(&configstr);
+    let config = Ini::load_from_file(&configstr).unwrap();
+    let daemon_config = config.section(Some("daemon")).unwrap();
 
+    if let Some(prefix) = matches.value_of("export") {
+        std::process::exit(export_client(&matches, daemon_config, prefix));
+    }
 
+    log4rs::init_file(matches.value_of("logging_config").unwrap_or("etc/log4rs.yml"), Default::default()).unwrap();
     log::info!("Using configuration file: {}", configstr);
-    let configpath = Path::new(&configstr);
-    let config = Ini::load_from_file(&configstr).unwrap();
     log::info!("Config path:{}", configpath.parent().unwrap().display());
 
-    let daemon_config = config.section(Some("daemon")).unwrap();
-
     let listen_ip = daemon_config.get("listen_ip").unwrap();
-    let listen_port = daemon_config.get("listen_port").unwrap();
-
-    let server_address = format!("{}:{}", listen_ip, listen_port);
+    // An empty listen_port leaves only the unix socket
+    let server_address = daemon_config
+        .get("listen_port")
+        .filter(|port| !port.is_empty())
+        .map(|port| format!("{}:{}", listen_ip, port));
 
     let welcome_string = Red.paint("Starting Sighting Daemon").to_string();
     log::info!("{}", welcome_string);
 
-    let use_ssl;
-    match daemon_config.get("ssl").unwrap().as_ref() {
-        "false" => use_ssl = false,
-        _ => use_ssl = true, // no mistake, only false can start the unsecure server.
-    }
+    let use_ssl = !matches!(daemon_config.get("ssl").unwrap().as_ref(), "false"); // no mistake, only false can start the unsecure server.
     match daemon_config.get("authenticate").unwrap().as_ref() {
         "false" => {
             sharedstate.lock().unwrap().authenticate = false;
@@ -598,22 +832,38 @@ fn main() {
         log::info!("{}", auth_string);
     }
 
-    let mut ssl_cert: PathBuf;
-    let ssl_cert_config = daemon_config.get("ssl_cert").unwrap();
-    if ssl_cert_config.starts_with('/') {
-        ssl_cert = PathBuf::from(ssl_cert_config);
-    } else {
-        ssl_cert = PathBuf::from(configpath.parent().unwrap());
-        ssl_cert.push(&ssl_cert_config);
+    let dbdir = daemon_config.get("dbdir").unwrap();
+    sharedstate.lock().unwrap().db.set_db_path(dbdir.to_string());
+    if let Err(e) = sharedstate.lock().unwrap().db.apikeys.open(dbdir) {
+        log::error!("Cannot load API keys: {}", e);
+        std::process::exit(1);
     }
 
-    let mut ssl_key: PathBuf;
-    let ssl_key_config = daemon_config.get("ssl_key").unwrap();
-    if ssl_key_config.starts_with('/') {
-        ssl_key = PathBuf::from(ssl_key_config);
-    } else {
-        ssl_key = PathBuf::from(configpath.parent().unwrap());
-        ssl_key.push(&ssl_key_config);
+    // Even without the section, so keys removed from it are revoked
+    if let Err(e) = sharedstate
+        .lock()
+        .unwrap()
+        .db
+        .apikeys
+        .load_config(config.section(Some("apikeys")))
+    {
+        log::error!("Invalid [apikeys] configuration: {}", e);
+        std::process::exit(1);
+    }
+
+    // Before daemonizing: a generated admin key must not end up in log_out
+    let apikeyarg = matches.value_of("apikey");
+    {
+        let apikeys = &mut sharedstate.lock().unwrap().db.apikeys;
+        if let Some(apikey) = apikeyarg {
+            // Replaces the key given the previous time, other keys are kept
+            apikeys.insert(apikey, apikeys::ApiKey::full_access("cli", "From the command line"));
+        } else if apikeys.is_empty() {
+            // No default key anymore, it would be the same on every install
+            let authkey = apikeys.create_admin();
+            println!("No API key found, generated the admin key (it will not be shown again): {}", authkey);
+            log::warn!("No API key found, generated an admin key with id 'admin'");
+        }
     }
 
     match daemon_config.get("daemonize").unwrap().as_ref() {
@@ -631,7 +881,77 @@ fn main() {
         _ => log::info!("Unknown daemon setting. Starting in foreground."),
     }
 
-    if use_ssl {
+    if let Some(section) = config.section(Some("client_certificates")) {
+        if let Err(e) = sharedstate.lock().unwrap().certificates.load_config(section) {
+            log::error!("Invalid [client_certificates] configuration: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(section) = config.section(Some("jwt")) {
+        match jwt::JwtVerifier::from_config(section, configpath.parent().unwrap()) {
+            Ok(verifier) => sharedstate.lock().unwrap().jwt = Some(verifier),
+            Err(e) => {
+                log::error!("Invalid [jwt] configuration: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(section) = config.section(Some("audit")) {
+        if let Err(e) = sharedstate.lock().unwrap().audit.load_config(section) {
+            log::error!("Invalid [audit] configuration: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    match sighting_configure::Configuration::from_ini(&config) {
+        Ok(configuration) => sharedstate.lock().unwrap().db.set_configuration(configuration),
+        Err(e) => {
+            log::error!("Invalid namespace configuration: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(top_capacity) = daemon_config.get("top_capacity") {
+        match top_capacity.parse::<usize>() {
+            Ok(capacity) => sharedstate.lock().unwrap().db.set_top_capacity(capacity),
+            Err(_) => log::error!("Invalid top_capacity: {}", top_capacity),
+        }
+    }
+
+    // routes:
+    // w -> write
+    // r -> read
+    // c -> config (push all to disk, alway in memory, both)
+    // i -> info
+    // untyped -> things that have an incorrect type match
+
+    let post_limit: usize = daemon_config
+        .get("post_limit")
+        .unwrap()
+        .parse()
+        .unwrap_or(2_500_000_000);
+
+    let acceptor = if use_ssl && server_address.is_some() {
+        let mut ssl_cert: PathBuf;
+        let ssl_cert_config = daemon_config.get("ssl_cert").unwrap();
+        if ssl_cert_config.starts_with('/') {
+            ssl_cert = PathBuf::from(ssl_cert_config);
+        } else {
+            ssl_cert = PathBuf::from(configpath.parent().unwrap());
+            ssl_cert.push(ssl_cert_config);
+        }
+
+        let mut ssl_key: PathBuf;
+        let ssl_key_config = daemon_config.get("ssl_key").unwrap();
+        if ssl_key_config.starts_with('/') {
+            ssl_key = PathBuf::from(ssl_key_config);
+        } else {
+            ssl_key = PathBuf::from(configpath.parent().unwrap());
+            ssl_key.push(ssl_key_config);
+        }
+
         let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
         builder
             .set_private_key_file(ssl_key, SslFiletype::PEM)
@@ -640,39 +960,172 @@ fn main() {
             .set_certificate_chain_file(ssl_cert.to_str().unwrap())
             .unwrap();
 
-        // routes:
-        // w -> write
-        // r -> read
-        // c -> config (push all to disk, alway in memory, both)
-        // i -> info
-        // untyped -> things that have an incorrect type match
-
-        let post_limit: usize = daemon_config
-            .get("post_limit")
-            .unwrap()
-            .parse()
-            .unwrap_or(2_500_000_000);
-
-        HttpServer::new(move || {
-            App::new()
-                .data(sharedstate.clone())
-                .route("/r/*", web::get().to(read))
-                .route("/rb", web::post().to(read_bulk))
-                .route("/rs/*", web::get().to(read_with_stats))
-                .route("/rbs", web::post().to(read_bulk_with_stats))
-                .route("/w/*", web::get().to(write))
-                .route("/wb", web::post().to(write_bulk))
-                .route("/c/*", web::get().to(configure))
-                .route("/i", web::get().to(info))
-                .route("/d/*", web::get().to(delete))
-                .default_service(web::to(help))
-                .data(web::JsonConfig::default().limit(post_limit))
+        if let Some(ca_config) = daemon_config.get("ssl_client_ca") {
+            let mut ca: PathBuf;
+            if ca_config.starts_with('/') {
+                ca = PathBuf::from(ca_config);
+            } else {
+                ca = PathBuf::from(configpath.parent().unwrap());
+                ca.push(ca_config);
+            }
+            // Clients may still authenticate with a key, but a certificate they send must be valid,
+            // unless ssl_client_cert_required=true and every client must send one
+            let mut verify = SslVerifyMode::PEER;
+            if daemon_config.get("ssl_client_cert_required").map(String::as_str) == Some("true") {
+                verify |= SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+            }
+            builder.set_verify(verify);
+            builder.set_ca_file(&ca).unwrap();
+            builder.set_client_ca_list(X509Name::load_client_ca_file(&ca).unwrap());
+        }
+        Some(builder.build())
+    } else {
+        None
+    };
+
+    // With ssl=false, listen_port serves plain HTTP. Otherwise http_listen_port may add a plain
+    // HTTP listener next to HTTPS, for a TLS-terminating proxy.
+    let http_address = if use_ssl {
+        daemon_config.get("http_listen_port").map(|port| {
+            let ip = daemon_config.get("http_listen_ip").unwrap_or(listen_ip);
+            format!("{}:{}", ip, port)
         })
-            .bind_ssl(server_address, builder)
-            .unwrap()
-            .run()
+    } else {
+        server_address.clone()
+    };
+
+    // Built by hand rather than with HttpServer, which does not give access to the
+    // TLS connection: its client certificate is read once, when the connection is made.
+    let sys = actix_rt::System::new("sightingdb");
+    let mut server = actix_server::Server::build();
+    if let (Some(acceptor), Some(server_address)) = (acceptor, &server_address) {
+        let state = sharedstate.clone();
+        log::info!("Listening for HTTPS on {}", server_address);
+        server = server
+            .bind("sightingdb", server_address, move || {
+                OpensslAcceptor::new(acceptor.clone())
+                    .map_err(SslError::Ssl)
+                    .and_then(
+                        HttpService::build()
+                            .on_connect(|io: &SslStream<TcpStream>| {
+                                client_cert::ClientCertificate::from_ssl(io.get_ref().ssl())
+                            })
+                            .finish(app(&state, post_limit))
+                            .map_err(SslError::Service)
+                            .map_init_err(|_| ()),
+                    )
+            })
+            .unwrap();
+    }
+    if let Some(http_address) = http_address {
+        let state = sharedstate.clone();
+        log::info!("Listening for plain HTTP on {}", http_address);
+        server = server
+            .bind("sightingdb-http", &http_address, move || {
+                HttpService::build().finish(app(&state, post_limit))
+            })
+            .unwrap();
+    }
+    if let Some(unix_socket) = daemon_config.get("unix_socket") {
+        let mode = match local_socket::parse_mode(daemon_config.get("unix_socket_mode").map_or("0660", String::as_str)) {
+            Ok(mode) => mode,
+            Err(e) => {
+                log::error!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        let listener = match local_socket::bind(Path::new(unix_socket), mode) {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::error!("Cannot listen on {}: {}", unix_socket, e);
+                std::process::exit(1);
+            }
+        };
+        if let Some(rules) = daemon_config.get("unix_socket_rules") {
+            match acl::parse_rules(rules) {
+                Ok(rules) => sharedstate.lock().unwrap().local_rules = Some(rules),
+                Err(e) => {
+                    log::error!("Invalid unix_socket_rules: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        let state = sharedstate.clone();
+        log::info!("Listening on unix socket {}", unix_socket);
+        server = server
+            .listen_uds("sightingdb-unix", listener, move || {
+                HttpService::build()
+                    .on_connect(|_: &UnixStream| local_socket::LocalConnection)
+                    .finish(app(&state, post_limit))
+            })
             .unwrap();
     }
+    server.start();
+    sys.run().unwrap();
+}
+
+// The same application is served by every listener
+fn app(
+    sharedstate: &Arc<Mutex<SharedState>>,
+    post_limit: usize,
+) -> App<
+    impl NewService<
+        Config = (),
+        Request = ServiceRequest,
+        Response = ServiceResponse<Body>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+    Body,
+> {
+    let authstate = sharedstate.clone();
+    App::new()
+        .data(sharedstate.clone())
+        // Resolves the API key once, handlers then check what the principal may do
+        .wrap_fn(move |req, srv| {
+            let event = audit::start(&req);
+            let auditstate = authstate.clone();
+            let response = match auth::resolve(&authstate, &req) {
+                Ok(principal) => {
+                    if let Some(principal) = principal {
+                        req.extensions_mut().insert(principal);
+                    }
+                    Either::A(srv.call(req))
+                }
+                Err(response) => Either::B(ok(req.into_response(response.into_body()))),
+            };
+            response.map(move |res| {
+                audit::finish(&auditstate, event, &res);
+                res
+            })
+        })
+        .route("/r/*", web::get().to(read))
+        .route("/rb", web::post().to(read_bulk))
+        .route("/rs/*", web::get().to(read_with_stats))
+        .route("/rbs", web::post().to(read_bulk_with_stats))
+        .route("/w/*", web::get().to(write))
+        .route("/wb", web::post().to(write_bulk))
+        .route("/ws", web::post().to(write_stream))
+        .route("/c/*", web::get().to(configure))
+        .route("/i", web::get().to(info))
+        .route("/d/*", web::get().to(delete))
+        .route("/ns/*", web::get().to(namespace_stats))
+        .route("/anomalies/*", web::get().to(anomalies))
+        .route("/top", web::get().to(top))
+        .route("/top/*", web::get().to(top))
+        .route("/export", web::get().to(export))
+        .route("/export/*", web::get().to(export))
+        .route("/audit", web::get().to(audit_log))
+        .route("/k", web::get().to(list_keys))
+        .route("/k", web::post().to(create_key))
+        .route("/k/{id}/rotate", web::post().to(rotate_key))
+        .route("/k/{id}", web::delete().to(revoke_key))
+        .default_service(web::to(help))
+        .data(
+            web::JsonConfig::default()
+                .limit(post_limit)
+                .error_handler(error::json_error),
+        )
 }
 
 fn info(_req: HttpRequest) -> impl Responder {
@@ -684,3 +1137,25 @@ fn info(_req: HttpRequest) -> impl Responder {
     };
     HttpResponse::Ok().json(&info_data)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bulk_read_filter() {
+        let postdata: PostData = serde_json::from_str(
+            r#"{"items":[{"namespace":"feeds/a","value":"YQ","noshadow":false}],"first_seen_after":1000,"last_seen_before":2000}"#,
+        )
+        .unwrap();
+        assert_eq!(postdata.items.len(), 1);
+        assert_eq!(
+            postdata.filter(),
+            TimeFilter {
+                first_seen_after: Some(1000),
+                last_seen_before: Some(2000),
+                ..TimeFilter::default()
+            }
+        );
+    }
+}
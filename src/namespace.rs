@@ -0,0 +1,250 @@
+use chrono::Utc;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::attribute::{apply_retention, stats_bucket, Attribute};
+use crate::heavy_hitters::{SpaceSaving, TopBy, TopItem, MAX_RECENT_HOURS};
+use crate::sighting_configure::NamespaceSettings;
+use crate::time_index::{TimeFilter, TimeIndex};
+
+// All the values of a namespace, along with the structures we maintain next to them.
+#[derive(Default)]
+pub struct Namespace {
+    pub attributes: HashMap<String, Attribute>,
+    pub time_index: TimeIndex,
+    pub top: SpaceSaving,
+    pub stats: NamespaceStats,
+    pub settings: NamespaceSettings,
+}
+
+// Namespace metadata, updated on every write so it never needs a scan
+#[derive(Serialize, Default, Clone)]
+pub struct NamespaceStats {
+    pub distinct_values: usize,
+    pub total_sightings: u128,
+    // Wall clock time of the first and last write to the namespace
+    pub first_write: i64,
+    pub last_write: i64,
+    // Sum of the attributes stats, with the same granularity and retention
+    pub stats: BTreeMap<i64, u128>,
+}
+
+impl NamespaceStats {
+    fn record(&mut self, attr: &Attribute, timestamp: i64, new_value: bool, settings: &NamespaceSettings) {
+        let now = Utc::now().timestamp();
+        if self.first_write == 0 {
+            self.first_write = now;
+        }
+        self.last_write = now;
+        if new_value {
+            self.distinct_values += 1;
+        }
+        self.total_sightings += 1;
+        // Same time Attribute::increment used for its own stats
+        let when = if timestamp.is_negative() {
+            attr.last_seen.timestamp()
+        } else {
+            timestamp
+        };
+        let bucket = stats_bucket(when, settings.stats_granularity);
+        if !self.stats.contains_key(&bucket) {
+            apply_retention(&mut self.stats, now, &settings.stats_retention);
+        }
+        *self.stats.entry(bucket).or_insert(0) += 1;
+    }
+}
+
+// Namespace reads order, highest first
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortBy {
+    Score,
+    Count,
+    FirstSeen,
+    LastSeen,
+}
+
+impl SortBy {
+    pub fn from_name(name: &str) -> Option<SortBy> {
+        match name {
+            "score" => Some(SortBy::Score),
+            "count" => Some(SortBy::Count),
+            "first_seen" => Some(SortBy::FirstSeen),
+            "last_seen" => Some(SortBy::LastSeen),
+            _ => None,
+        }
+    }
+}
+
+// Start of the oldest stats bucket covered by the last `hours` hours, current hour included.
+// Buckets coarser than an hour, or rolled up by stats_retention, are counted whole, as
+// window_counts does.
+pub fn recent_since(now: i64, hours: i64, settings: &NamespaceSettings) -> i64 {
+    let hours = hours.clamp(1, MAX_RECENT_HOURS);
+    let since = now - now.rem_euclid(3600) - (hours - 1) * 3600;
+    let granularity = settings
+        .stats_retention
+        .iter()
+        .find(|rule| now - since < rule.max_age)
+        .map_or(settings.stats_granularity, |rule| rule.granularity);
+    stats_bucket(since, granularity)
+}
+
+impl Namespace {
+    pub fn new(top_capacity: usize, settings: NamespaceSettings) -> Namespace {
+        Namespace {
+            top: SpaceSaving::new(top_capacity),
+            settings,
+            ..Namespace::default()
+        }
+    }
+
+    // Returns the updated attribute and whether it was already there.
+    pub fn increment(&mut self, value: &str, timestamp: i64) -> (Attribute, bool) {
+        let (attr, previous) = match self.attributes.get_mut(value) {
+            Some(attr) => {
+                let previous = (attr.first_seen.timestamp(), attr.last_seen.timestamp());
+                attr.increment(timestamp, &self.settings);
+                (attr.clone(), Some(previous))
+            }
+            None => {
+                let mut attr = Attribute::new(value);
+                attr.increment(timestamp, &self.settings);
+                self.attributes.insert(value.to_string(), attr.clone());
+                (attr, None)
+            }
+        };
+        self.time_index.update(
+            value,
+            previous,
+            (attr.first_seen.timestamp(), attr.last_seen.timestamp()),
+        );
+        self.top.offer(value, 1);
+        self.stats
+            .record(&attr, timestamp, previous.is_none(), &self.settings);
+        (attr, previous.is_some())
+    }
+
+    // Sightings per value over the last `hours` hours, only walking recently seen values
+    pub fn recent_counts(&self, now: i64, hours: i64) -> impl Iterator<Item = (&str, u128)> {
+        let since = recent_since(now, hours, &self.settings);
+        let filter = TimeFilter {
+            last_seen_after: Some(since),
+            ..TimeFilter::default()
+        };
+        self.time_index
+            .candidates(&filter)
+            .filter_map(move |value| self.attributes.get(value))
+            .map(move |attr| (attr.value.as_str(), attr.stats.range(since..).map(|(_, c)| c).sum()))
+    }
+
+    pub fn top(&self, n: usize, by: TopBy, now: i64) -> Vec<TopItem> {
+        let mut items: Vec<TopItem> = match by {
+            // Space-Saving gives the candidates, the attributes give their exact count
+            TopBy::Count => self
+                .top
+                .top(usize::MAX)
+                .into_iter()
+                .filter_map(|item| self.attributes.get(&item.value))
+                .map(|attr| TopItem {
+                    value: attr.value.clone(),
+                    count: attr.count,
+                    error: None,
+                })
+                .collect(),
+            TopBy::RecentCount { hours } => self
+                .recent_counts(now, hours)
+                .filter(|(_, count)| *count > 0)
+                .map(|(value, count)| TopItem {
+                    value: value.to_string(),
+                    count,
+                    error: None,
+                })
+                .collect(),
+        };
+        items.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+        items.truncate(n);
+        items
+    }
+
+    pub fn sorted<'a>(&'a self, filter: &TimeFilter, sort: SortBy, now: i64) -> Vec<&'a Attribute> {
+        let mut attributes: Vec<&Attribute> = self.filtered(filter).collect();
+        let half_life = self.settings.score_half_life;
+        match sort {
+            SortBy::Score => attributes.sort_by(|a, b| {
+                b.score_at(now, half_life)
+                    .total_cmp(&a.score_at(now, half_life))
+            }),
+            SortBy::Count => attributes.sort_by_key(|attr| Reverse(attr.count)),
+            SortBy::FirstSeen => attributes.sort_by_key(|attr| Reverse(attr.first_seen)),
+            SortBy::LastSeen => attributes.sort_by_key(|attr| Reverse(attr.last_seen)),
+        }
+        attributes
+    }
+
+    pub fn filtered<'a>(&'a self, filter: &TimeFilter) -> Box<dyn Iterator<Item = &'a Attribute> + 'a> {
+        if filter.is_empty() {
+            return Box::new(self.attributes.values());
+        }
+        let filter = *filter;
+        Box::new(
+            self.time_index
+                .candidates(&filter)
+                .filter_map(move |value| self.attributes.get(value))
+                .filter(move |attr| filter.matches(attr)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sighting_configure::RetentionRule;
+
+    #[test]
+    fn test_stats_record_writes() {
+        let before = Utc::now().timestamp();
+        let mut namespace = Namespace::default();
+        namespace.increment("a", 7200 + 10);
+        namespace.increment("b", 7200 + 20);
+        namespace.increment("a", 3 * 3600 + 5);
+
+        let stats = &namespace.stats;
+        assert_eq!(stats.distinct_values, 2);
+        assert_eq!(stats.total_sightings, 3);
+        assert!(stats.first_write >= before);
+        assert!(stats.last_write >= stats.first_write);
+        assert_eq!(stats.stats, BTreeMap::from([(7200, 2), (3 * 3600, 1)]));
+    }
+
+    #[test]
+    fn test_recent_since_follows_granularity() {
+        let now = 10 * 86400 + 5 * 3600 + 60;
+        let mut settings = NamespaceSettings::default();
+        assert_eq!(recent_since(now, 2, &settings), now - 60 - 3600);
+        // Out of range hours are clamped
+        assert_eq!(recent_since(now, 0, &settings), recent_since(now, 1, &settings));
+        assert_eq!(
+            recent_since(now, i64::MAX, &settings),
+            recent_since(now, MAX_RECENT_HOURS, &settings)
+        );
+
+        settings.stats_granularity = 86400;
+        assert_eq!(recent_since(now, 2, &settings), 10 * 86400);
+
+        settings.stats_granularity = 3600;
+        settings.stats_retention = vec![
+            RetentionRule {
+                granularity: 3600,
+                max_age: 86400,
+            },
+            RetentionRule {
+                granularity: 86400,
+                max_age: 30 * 86400,
+            },
+        ];
+        assert_eq!(recent_since(now, 48, &settings), 8 * 86400);
+        // Past the retention, back to the stats granularity
+        assert_eq!(recent_since(now, 24 * 365, &settings), now - 60 - (24 * 365 - 1) * 3600);
+    }
+}
@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::acl::Principal;
+use crate::error::Error;
+
+const DAY: i64 = 86400;
+
+// Set on a key when it is created, nothing is limited by default
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Limits {
+    // Requests per second, and how many may be made at once (the rate, at least 1, when unset)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_rate: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_burst: Option<f64>,
+    // Writes and deletes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub write_rate: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub write_burst: Option<f64>,
+    // Sightings written and values never seen before in their namespace, per UTC day
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily_writes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily_new_values: Option<u64>,
+}
+
+pub fn too_many_requests(message: &str, retry_after: u64) -> Error {
+    Error::TooManyRequests {
+        message: message.to_string(),
+        retry_after,
+    }
+}
+
+#[derive(Default)]
+struct TokenBucket {
+    tokens: f64,
+    updated: f64,
+    started: bool,
+}
+
+impl TokenBucket {
+    // Err with the seconds to wait for the next token
+    fn take(&mut self, rate: f64, burst: Option<f64>, now: f64) -> Result<(), f64> {
+        let burst = burst.unwrap_or(rate).max(1.0);
+        if !self.started {
+            self.tokens = burst;
+            self.started = true;
+        } else {
+            self.tokens = (self.tokens + (now - self.updated) * rate).min(burst);
+        }
+        self.updated = now;
+        if self.tokens < 1.0 {
+            return Err((1.0 - self.tokens) / rate);
+        }
+        self.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Usage {
+    read: TokenBucket,
+    write: TokenBucket,
+    day: i64,
+    writes: u64,
+    new_values: u64,
+}
+
+// Usage per principal, in memory only: a restart gives every key full buckets and quotas
+#[derive(Default)]
+pub struct RateLimiter {
+    usage: HashMap<String, Usage>,
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter::default()
+    }
+
+    // Once per request, Err with the seconds to wait before retrying
+    pub fn check_rate(&mut self, principal: &Principal, write: bool, now: f64) -> Result<(), u64> {
+        let limits = &principal.limits;
+        let (rate, burst) = if write {
+            (limits.write_rate, limits.write_burst)
+        } else {
+            (limits.read_rate, limits.read_burst)
+        };
+        let rate = match rate {
+            Some(rate) if rate > 0.0 => rate,
+            _ => return Ok(()),
+        };
+        let usage = self.usage.entry(principal.id.clone()).or_default();
+        let bucket = if write { &mut usage.write } else { &mut usage.read };
+        bucket.take(rate, burst, now).map_err(|wait| wait.ceil() as u64)
+    }
+
+    // Before writing: counts the sightings and new values if they fit in today's quotas,
+    // Err with the seconds until the quotas are reset otherwise
+    pub fn check_quota(&mut self, principal: &Principal, writes: u64, new_values: u64, now: i64) -> Result<(), u64> {
+        let limits = &principal.limits;
+        if limits.daily_writes.is_none() && limits.daily_new_values.is_none() {
+            return Ok(());
+        }
+        let usage = self.usage.entry(principal.id.clone()).or_default();
+        let today = now - now.rem_euclid(DAY);
+        if usage.day != today {
+            usage.day = today;
+            usage.writes = 0;
+            usage.new_values = 0;
+        }
+        let over = |limit: Option<u64>, used: u64, more: u64| limit.is_some_and(|limit| used + more > limit);
+        if over(limits.daily_writes, usage.writes, writes)
+            || over(limits.daily_new_values, usage.new_values, new_values)
+        {
+            return Err((today + DAY - now) as u64);
+        }
+        usage.writes += writes;
+        usage.new_values += new_values;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_and_quota() {
+        let mut principal = Principal::anonymous();
+        principal.limits = Limits {
+            write_rate: Some(2.0),
+            write_burst: Some(2.0),
+            daily_new_values: Some(3),
+            ..Limits::default()
+        };
+        let mut limiter = RateLimiter::new();
+
+        assert!(limiter.check_rate(&principal, true, 100.0).is_ok());
+        assert!(limiter.check_rate(&principal, true, 100.0).is_ok());
+        assert_eq!(limiter.check_rate(&principal, true, 100.0), Err(1));
+        assert!(limiter.check_rate(&principal, true, 100.5).is_ok());
+        // Reads are not limited
+        assert!(limiter.check_rate(&principal, false, 100.5).is_ok());
+
+        let now = 10 * DAY + 3600;
+        assert!(limiter.check_quota(&principal, 5, 2, now).is_ok());
+        assert_eq!(limiter.check_quota(&principal, 2, 2, now), Err((DAY - 3600) as u64));
+        assert!(limiter.check_quota(&principal, 2, 1, now).is_ok());
+        assert!(limiter.check_quota(&principal, 1, 3, now + DAY).is_ok());
+    }
+}
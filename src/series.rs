@@ -0,0 +1,192 @@
+use qstring::QString;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+// A dense series longer than this is most likely a mistake in from= or to=
+pub const MAX_POINTS: i64 = 10_000;
+const DEFAULT_RANGE: i64 = 7 * 86400;
+// from= and to= are clamped between the epoch and this long after now
+const MAX_FUTURE: i64 = 7 * 86400;
+// 1970-01-05 was a Monday, weeks start on Mondays
+const FIRST_MONDAY: i64 = 4 * 86400;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Bucket {
+    Hour,
+    Day,
+    Week,
+}
+
+impl Bucket {
+    pub fn from_name(name: &str) -> Option<Bucket> {
+        match name {
+            "hour" => Some(Bucket::Hour),
+            "day" => Some(Bucket::Day),
+            "week" => Some(Bucket::Week),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Bucket::Hour => "hour",
+            Bucket::Day => "day",
+            Bucket::Week => "week",
+        }
+    }
+
+    pub fn seconds(self) -> i64 {
+        match self {
+            Bucket::Hour => 3600,
+            Bucket::Day => 86400,
+            Bucket::Week => 7 * 86400,
+        }
+    }
+
+    // Start of the bucket the timestamp falls in
+    pub fn floor(self, timestamp: i64) -> i64 {
+        match self {
+            Bucket::Week => timestamp - (timestamp - FIRST_MONDAY).rem_euclid(self.seconds()),
+            _ => timestamp - timestamp.rem_euclid(self.seconds()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SeriesQuery {
+    pub from: i64,
+    pub to: i64,
+    pub bucket: Bucket,
+}
+
+impl SeriesQuery {
+    // Ok(None) when none of from=, to= and bucket= are in the query string.
+    // to defaults to now, from to a week before to, bucket to hour.
+    pub fn from_query(query_string: &QString, now: i64) -> Result<Option<SeriesQuery>, String> {
+        let from = query_string.get("from");
+        let to = query_string.get("to");
+        let bucket = query_string.get("bucket");
+        if from.is_none() && to.is_none() && bucket.is_none() {
+            return Ok(None);
+        }
+
+        let parse = |name: &str, v: &str| {
+            v.parse::<i64>()
+                .map_err(|_| format!("{}= must be a unix timestamp.", name))
+        };
+        let to = match to {
+            Some(v) => parse("to", v)?,
+            None => now,
+        };
+        let from = match from {
+            Some(v) => parse("from", v)?,
+            None => to - DEFAULT_RANGE,
+        };
+        let bucket = match bucket {
+            Some(v) => Bucket::from_name(v)
+                .ok_or_else(|| String::from("bucket= must be hour, day or week."))?,
+            None => Bucket::Hour,
+        };
+
+        if from > to {
+            return Err(String::from("from= must not be after to="));
+        }
+        let limit = now.saturating_add(MAX_FUTURE);
+        let query = SeriesQuery {
+            from: from.clamp(0, limit),
+            to: to.clamp(0, limit),
+            bucket,
+        };
+        match query.len() {
+            Some(len) if len > 0 && len <= MAX_POINTS => Ok(Some(query)),
+            _ => Err(format!(
+                "The range would return more than {} points, use a larger bucket.",
+                MAX_POINTS
+            )),
+        }
+    }
+
+    fn start(&self) -> i64 {
+        self.bucket.floor(self.from)
+    }
+
+    // None when the number of buckets does not fit
+    fn len(&self) -> Option<i64> {
+        let span = self.bucket.floor(self.to).checked_sub(self.start())?;
+        (span / self.bucket.seconds()).checked_add(1)
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Series {
+    pub bucket: &'static str,
+    pub from: i64,
+    pub to: i64,
+    // [bucket start, count] for every bucket between from and to, empty ones included
+    pub points: Vec<(i64, u128)>,
+}
+
+// Sums the stats buckets into the query buckets
+pub fn aggregate(stats: &BTreeMap<i64, u128>, query: &SeriesQuery) -> Series {
+    let start = query.start();
+    let step = query.bucket.seconds();
+    // from_query only builds queries whose length fits
+    let len = query.len().filter(|len| *len > 0 && *len <= MAX_POINTS).unwrap_or(0);
+    let mut points: Vec<(i64, u128)> = (0..len).map(|i| (start + i * step, 0)).collect();
+    let end = start + len * step;
+
+    for (timestamp, count) in stats.range(start..end) {
+        points[((timestamp - start) / step) as usize].1 += count;
+    }
+
+    Series {
+        bucket: query.bucket.name(),
+        from: query.from,
+        to: query.to,
+        points,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aggregate_zero_fills() {
+        let mut stats = BTreeMap::new();
+        stats.insert(0, 1);
+        stats.insert(3600, 2);
+        stats.insert(2 * 86400 + 3600, 5);
+        stats.insert(10 * 86400, 7);
+
+        let query = SeriesQuery {
+            from: 1800,
+            to: 2 * 86400 + 7200,
+            bucket: Bucket::Day,
+        };
+        let series = aggregate(&stats, &query);
+        assert_eq!(series.points, vec![(0, 3), (86400, 0), (2 * 86400, 5)]);
+    }
+
+    #[test]
+    fn test_extreme_bounds() {
+        let now = 1_600_000_000;
+        let query = |q: &str| SeriesQuery::from_query(&QString::from(q), now);
+        assert!(query("from=-9000000000000000000&to=9000000000000000000").is_err());
+        assert!(query("from=-9223372036854775808").is_err());
+
+        // Clamped between the epoch and a week after now
+        let series = query("from=-9223372036854775808&to=3600").unwrap().unwrap();
+        assert_eq!(series.from, 0);
+        assert_eq!(aggregate(&BTreeMap::new(), &series).points, vec![(0, 0), (3600, 0)]);
+        let series = query("from=9223372036854775000&to=9223372036854775807&bucket=week").unwrap().unwrap();
+        assert_eq!((series.from, series.to), (now + MAX_FUTURE, now + MAX_FUTURE));
+        assert_eq!(aggregate(&BTreeMap::new(), &series).points.len(), 1);
+    }
+
+    #[test]
+    fn test_week_starts_on_monday() {
+        // 2020-04-16 is a Thursday, its week started on Monday 2020-04-13
+        assert_eq!(Bucket::Week.floor(1587038400), 1586736000);
+    }
+}
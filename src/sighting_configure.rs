@@ -1,45 +1,248 @@
-// use std::collections::HashMap;
-
-// #[derive(PartialEq, Eq)]
-// pub enum ConfigStorage {
-//     IN_MEMORY,
-//     ON_DISK,
-// }
-
-// pub struct Configuration {
-//     storage: HashMap<String, ConfigStorage>,
-// }
-
-// impl Configuration {
-//     pub fn new() -> Configuration {
-//         Configuration {
-//             storage: HashMap::new(),
-//         }
-//     }
-//     pub fn set_storage(&mut self, storage: ConfigStorage, path: String) {
-//         self.storage.insert(path, storage);
-//     }
-//     pub fn get_storage(&mut self, path: String) -> String {
-//         let storageopt = self.storage.get_mut(&path);
-//         match storageopt {
-//             Some(storageopt) => {
-//                 match storageopt {
-//                     ConfigStorage::IN_MEMORY => { return String::from("IN_MEMORY"); },
-//                     ConfigStorage::ON_DISK => { return String::from("ON_DISK"); },
-//                 }
-//             },
-//             None => {
-//                 return String::from("IN_MEMORY");
-//             }
-//         }
-//     }
-
-// }
-
-// pub fn set(path: &str, value: &str) {
-//     println!("Configuring path {}", path);
-// }
-
-// pub fn get(path: &str, value: &str) {
-//     println!("Get configuration");
-// }
+use ini::ini::Properties;
+use ini::Ini;
+use std::cmp::Reverse;
+use std::str::FromStr;
+
+use crate::anomaly::SpikeSettings;
+
+// Per namespace settings, read from the configuration file:
+//
+// [namespace]                    <- defaults for every namespace
+// stats_granularity=hour
+// stats_retention=
+// spike_baseline=24              <- see anomaly.rs
+// spike_threshold=3
+// spike_min_count=10
+// count_windows=1h,24h,7d        <- sighting counts over these windows are added to reads
+// score_half_life=7d             <- time for a sighting to weigh half in the relevance score
+//
+// [namespace:my/feeds/]          <- namespaces starting with my/feeds/
+// stats_granularity=minute
+// stats_retention=minute:1d,hour:7d,day:365d
+//
+// The longest matching prefix wins, unset keys fall back to [namespace].
+
+const SECTION: &str = "namespace";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetentionRule {
+    // Stats buckets younger than max_age are kept with this granularity, in seconds
+    pub granularity: i64,
+    pub max_age: i64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NamespaceSettings {
+    // Width of the stats buckets, in seconds
+    pub stats_granularity: i64,
+    // From the finest to the coarsest granularity. Older buckets than the last
+    // rule are dropped, an empty list keeps every bucket forever.
+    pub stats_retention: Vec<RetentionRule>,
+    pub spike: SpikeSettings,
+    // Label as configured (e.g. 24h) and length in seconds
+    pub count_windows: Vec<(String, i64)>,
+    // In seconds
+    pub score_half_life: i64,
+}
+
+impl Default for NamespaceSettings {
+    fn default() -> Self {
+        NamespaceSettings {
+            stats_granularity: 3600,
+            stats_retention: Vec::new(),
+            spike: SpikeSettings::default(),
+            count_windows: vec![
+                (String::from("1h"), 3600),
+                (String::from("24h"), 86400),
+                (String::from("7d"), 7 * 86400),
+            ],
+            score_half_life: 7 * 86400,
+        }
+    }
+}
+
+fn parse_number<T: FromStr>(properties: &Properties, key: &str, number: &mut T) -> Result<(), String> {
+    if let Some(v) = properties.get(key) {
+        *number = v
+            .parse::<T>()
+            .map_err(|_| format!("Invalid {} '{}'", key, v))?;
+    }
+    Ok(())
+}
+
+fn parse_granularity(name: &str) -> Result<i64, String> {
+    match name {
+        "minute" => Ok(60),
+        "hour" => Ok(3600),
+        "day" => Ok(86400),
+        _ => Err(format!("Unknown granularity '{}', use minute, hour or day", name)),
+    }
+}
+
+// 90m, 36h, 7d, 52w or forever
+fn parse_age(age: &str) -> Result<i64, String> {
+    if age == "forever" {
+        return Ok(i64::MAX);
+    }
+    let error = || format!("Invalid duration '{}'", age);
+    let (number, unit) = match age.char_indices().last() {
+        Some((i, _)) => age.split_at(i),
+        None => return Err(error()),
+    };
+    let number = match number.parse::<i64>() {
+        Ok(number) if number > 0 => number,
+        _ => return Err(error()),
+    };
+    let unit = match unit {
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        "w" => 7 * 86400,
+        _ => return Err(error()),
+    };
+    number.checked_mul(unit).ok_or_else(error)
+}
+
+fn parse_retention(retention: &str) -> Result<Vec<RetentionRule>, String> {
+    let mut rules: Vec<RetentionRule> = Vec::new();
+    for rule in retention.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+        let mut parts = rule.splitn(2, ':');
+        let granularity = parse_granularity(parts.next().unwrap_or(""))?;
+        let max_age = parse_age(parts.next().unwrap_or(""))?;
+        if let Some(previous) = rules.last() {
+            if granularity < previous.granularity
+                || max_age <= previous.max_age
+                || granularity % previous.granularity != 0
+            {
+                return Err(format!(
+                    "Retention rule '{}' must be coarser and longer than the previous one",
+                    rule
+                ));
+            }
+        }
+        rules.push(RetentionRule {
+            granularity,
+            max_age,
+        });
+    }
+    Ok(rules)
+}
+
+impl NamespaceSettings {
+    fn merge(&self, properties: &Properties) -> Result<NamespaceSettings, String> {
+        let mut settings = self.clone();
+        if let Some(granularity) = properties.get("stats_granularity") {
+            settings.stats_granularity = parse_granularity(granularity)?;
+        }
+        if let Some(retention) = properties.get("stats_retention") {
+            settings.stats_retention = parse_retention(retention)?;
+        }
+        if let Some(windows) = properties.get("count_windows") {
+            settings.count_windows = windows
+                .split(',')
+                .map(str::trim)
+                .filter(|w| !w.is_empty())
+                .map(|w| parse_age(w).map(|seconds| (w.to_string(), seconds)))
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(half_life) = properties.get("score_half_life") {
+            settings.score_half_life = parse_age(half_life)?;
+            if settings.score_half_life <= 0 {
+                return Err(String::from("score_half_life must be positive"));
+            }
+        }
+        parse_number(properties, "spike_baseline", &mut settings.spike.baseline)?;
+        parse_number(properties, "spike_threshold", &mut settings.spike.threshold)?;
+        parse_number(properties, "spike_min_count", &mut settings.spike.min_count)?;
+        if let Some(finest) = settings.stats_retention.first() {
+            if finest.granularity != settings.stats_granularity {
+                return Err(String::from(
+                    "The first stats_retention rule must use the stats_granularity",
+                ));
+            }
+        }
+        Ok(settings)
+    }
+}
+
+#[derive(Default)]
+pub struct Configuration {
+    default: NamespaceSettings,
+    // Sorted by decreasing prefix length, so the first match is the longest
+    namespaces: Vec<(String, NamespaceSettings)>,
+}
+
+impl Configuration {
+    pub fn new() -> Configuration {
+        Configuration::default()
+    }
+
+    pub fn from_ini(config: &Ini) -> Result<Configuration, String> {
+        let mut configuration = Configuration::new();
+        if let Some(properties) = config.section(Some(SECTION)) {
+            configuration.default = configuration.default.merge(properties)?;
+        }
+        for (section, properties) in config.iter() {
+            let prefix = match section.as_ref().and_then(|s| s.strip_prefix("namespace:")) {
+                Some(prefix) => prefix,
+                None => continue,
+            };
+            let settings = configuration
+                .default
+                .merge(properties)
+                .map_err(|e| format!("[{}:{}] {}", SECTION, prefix, e))?;
+            configuration.namespaces.push((prefix.to_string(), settings));
+        }
+        configuration
+            .namespaces
+            .sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+        Ok(configuration)
+    }
+
+    pub fn get(&self, namespace: &str) -> &NamespaceSettings {
+        self.namespaces
+            .iter()
+            .find(|(prefix, _)| namespace.starts_with(prefix.as_str()))
+            .map_or(&self.default, |(_, settings)| settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_longest_prefix_wins() {
+        let config = Ini::load_from_str(
+            "[namespace]\nstats_granularity=day\n\
+             [namespace:feeds/]\nstats_granularity=hour\nstats_retention=hour:7d,day:forever\n\
+             [namespace:feeds/fast/]\nstats_granularity=minute\nstats_retention=minute:1d\n",
+        )
+        .unwrap();
+        let configuration = Configuration::from_ini(&config).unwrap();
+
+        assert_eq!(configuration.get("other").stats_granularity, 86400);
+        assert_eq!(configuration.get("feeds/slow").stats_granularity, 3600);
+        assert_eq!(
+            configuration.get("feeds/slow").stats_retention[1],
+            RetentionRule {
+                granularity: 86400,
+                max_age: i64::MAX
+            }
+        );
+        assert_eq!(configuration.get("feeds/fast/x").stats_granularity, 60);
+
+        let config = Ini::load_from_str("[namespace]\nstats_retention=day:7d,hour:30d\n").unwrap();
+        assert!(Configuration::from_ini(&config).is_err());
+    }
+
+    #[test]
+    fn test_parse_age() {
+        assert_eq!(parse_age("90m"), Ok(90 * 60));
+        assert_eq!(parse_age("52w"), Ok(52 * 7 * 86400));
+        assert_eq!(parse_age("forever"), Ok(i64::MAX));
+        for invalid in ["", "5", "5é", "é", "0d", "-1h", "9223372036854775807w"] {
+            assert!(parse_age(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
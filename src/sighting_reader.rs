@@ -1,9 +1,85 @@
+use crate::acl;
+use crate::anomaly::Spike;
+use crate::attribute::NOW;
 use crate::db::Database;
+use crate::error::Error;
+use crate::heavy_hitters::{TopBy, TopItem};
+use crate::namespace::{NamespaceStats, SortBy};
+use crate::series::SeriesQuery;
+use crate::time_index::TimeFilter;
+use chrono::Utc;
 use serde::Serialize;
+use serde_json::{Map, Value};
 
 #[derive(Serialize)]
-pub struct Message {
-    message: String,
+pub struct NamespaceStatsResponse<'a> {
+    namespace: &'a str,
+    #[serde(flatten)]
+    stats: NamespaceStats,
+}
+
+#[derive(Serialize)]
+pub struct SpikingValue<'a> {
+    value: &'a str,
+    #[serde(flatten)]
+    spike: Spike,
+}
+
+#[derive(Serialize)]
+pub struct AnomaliesResponse<'a> {
+    namespace: &'a str,
+    spike: Spike,
+    values: Vec<SpikingValue<'a>>,
+}
+
+#[derive(Serialize)]
+pub struct TopResponse {
+    items: Vec<TopItem>,
+}
+
+// One item of a bulk read. The attribute fields are those of /r, status is "ok", "filtered_out"
+// when the value is outside of the time window, or the code of the error the item ended with.
+#[derive(Serialize)]
+pub struct BulkReadItem<'a> {
+    namespace: &'a str,
+    value: &'a str,
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    #[serde(flatten)]
+    attribute: Map<String, Value>,
+    // Only asked by /rbs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<Value>,
+}
+
+impl<'a> BulkReadItem<'a> {
+    pub fn failed(namespace: &'a str, value: &'a str, e: &Error) -> BulkReadItem<'a> {
+        BulkReadItem {
+            namespace,
+            value,
+            status: e.code(),
+            message: Some(e.to_string()),
+            attribute: Map::new(),
+            stats: None,
+        }
+    }
+
+    pub fn filtered_out(namespace: &'a str, value: &'a str) -> BulkReadItem<'a> {
+        BulkReadItem {
+            namespace,
+            value,
+            status: "filtered_out",
+            message: None,
+            attribute: Map::new(),
+            stats: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct BulkReadResponse<'a> {
+    pub items: Vec<BulkReadItem<'a>>,
 }
 
 // #[derive(Deserialize)]
@@ -13,48 +89,158 @@ pub struct Message {
 //     value: String
 // }
 
-pub fn read(db: &mut Database, path: &str, value: &str, with_stats: bool, with_shadow: bool) -> String {
-    if path.starts_with("_config/") {
-        let err = serde_json::to_string(&Message {
-            message: String::from("No access to _config namespace from outside!"),
-        })
-        .unwrap();
-        return err;
-    }
-
-    let consensus = db.get_count(&"_all".to_string(), value);
+pub fn read(db: &mut Database, path: &str, value: &str, with_stats: bool, with_shadow: bool) -> Result<String, Error> {
+    let consensus = db.get_count("_all", value);
     let attr = db.get_attr(path, value, with_stats, consensus);
     
-    // Shadow Sightings
     if with_shadow {
-        let mut shadow_path: String = "_shadow/".to_owned();
-        shadow_path.push_str(path);
-        // _shadow does not write the consensus
-        db.write(&shadow_path, value, 0, false);
+        shadow_sighting(db, path, value);
     }
     
     attr
 }
 
-pub fn read_namespace(db: &mut Database, namespace: &str) -> String {
-    if namespace.starts_with("_config/") {
-        let err = serde_json::to_string(&Message {
-            message: String::from("No access to _config namespace from outside!"),
-        })
-        .unwrap();
-        return err;
+// Same as read with stats, but only the stats within the query range, aggregated per bucket
+pub fn read_series(db: &mut Database, path: &str, value: &str, query: &SeriesQuery, with_shadow: bool) -> Result<String, Error> {
+    let consensus = db.get_count("_all", value);
+    let attr = db.get_attr_with(path, value, consensus, |attr, settings| {
+        let now = Utc::now().timestamp();
+        let mut fields = attr.read_fields(now, settings);
+        fields.push(("series", serde_json::to_value(attr.series(query))?));
+        fields.push(("spike", serde_json::to_value(attr.spike(now, settings))?));
+        Ok(attr.serialize_with(fields)?)
+    });
+
+    if with_shadow {
+        shadow_sighting(db, path, value);
     }
 
-    let namespace_with_values = db.get_namespace_attrs(namespace);
+    attr
+}
+
+// Same as read, for one item of a bulk read: an error is the status of the item, not of the read
+pub fn read_item<'a>(db: &mut Database, path: &'a str, value: &'a str, with_stats: bool, with_shadow: bool) -> BulkReadItem<'a> {
+    let consensus = db.get_count("_all", value);
+    let attr = db.get_attr_with(path, value, consensus, |attr, settings| {
+        let now = Utc::now().timestamp();
+        let mut fields = attr.read_fields(now, settings);
+        if with_stats {
+            fields.push(("spike", serde_json::to_value(attr.spike(now, settings))?));
+        }
+        let attribute = match attr.to_value_with(fields)? {
+            Value::Object(mut attribute) => {
+                // Already echoed by the item
+                attribute.remove("value");
+                attribute
+            }
+            _ => Map::new(),
+        };
+        let stats = if with_stats {
+            Some(serde_json::to_value(&attr.stats)?)
+        } else {
+            None
+        };
+        Ok((attribute, stats))
+    });
 
-    namespace_with_values
+    if with_shadow {
+        shadow_sighting(db, path, value);
+    }
+
+    match attr {
+        Ok((attribute, stats)) => BulkReadItem {
+            namespace: path,
+            value,
+            status: "ok",
+            message: None,
+            attribute,
+            stats,
+        },
+        Err(e) => BulkReadItem::failed(path, value, &e),
+    }
+}
+
+// Shadow Sightings: we count how many times a value was read
+fn shadow_sighting(db: &mut Database, path: &str, value: &str) {
+    // Reading what the database keeps for itself is not worth a sighting
+    if acl::reserved(path).is_some() {
+        return;
+    }
+    let mut shadow_path: String = "_shadow/".to_owned();
+    shadow_path.push_str(path);
+    // _shadow does not write the consensus
+    db.write(&shadow_path, value, NOW, false);
+}
+
+pub fn read_namespace(db: &mut Database, namespace: &str, filter: &TimeFilter, sort: Option<SortBy>) -> Result<String, Error> {
+    db.get_namespace_attrs(namespace, filter, sort)
+}
+
+pub fn read_namespace_stats(db: &mut Database, namespace: &str) -> Result<String, Error> {
+    match db.get_namespace_stats(namespace) {
+        Some(stats) => Ok(serde_json::to_string(&NamespaceStatsResponse { namespace, stats })?),
+        None => Err(Error::NamespaceNotFound(namespace.to_string())),
+    }
+}
+
+pub fn read_anomalies(db: &mut Database, namespace: &str) -> Result<String, Error> {
+    match db.get_anomalies(namespace, Utc::now().timestamp()) {
+        Some((spike, values)) => Ok(serde_json::to_string(&AnomaliesResponse {
+            namespace,
+            spike,
+            values: values
+                .into_iter()
+                .map(|(value, spike)| SpikingValue { value, spike })
+                .collect(),
+        })?),
+        None => Err(Error::NamespaceNotFound(namespace.to_string())),
+    }
+}
+
+// namespace is None for the top values across all namespaces
+pub fn read_top(db: &mut Database, namespace: Option<&str>, n: usize, by: TopBy) -> Result<String, Error> {
+    match db.get_top(namespace, n, by, Utc::now().timestamp()) {
+        Some(items) => Ok(serde_json::to_string(&TopResponse { items })?),
+        None => Err(Error::NamespaceNotFound(namespace.unwrap_or("").to_string())),
+    }
 }
 
 // Our internal reading does not trigger shadow sightings.
 // USELESS FOR NOW, but will need to reactivate once we have the possibility to skip shadow if we want to
 // pub fn read_internal(db: &mut Database, path: &str, value: &str, with_stats: bool) -> String {
-//     let consensus = db.get_count(&"_all".to_string(), value);
+//     let consensus = db.get_count("_all", value);
 //     let attr = db.get_attr(path, value, with_stats, consensus);
 
 //     return attr;
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_item() {
+        let mut db = Database::new();
+        db.write("ns", "YQ", 1000, true);
+
+        let found = serde_json::to_value(read_item(&mut db, "ns", "YQ", true, false)).unwrap();
+        assert_eq!(found["namespace"], "ns");
+        assert_eq!(found["value"], "YQ");
+        assert_eq!(found["status"], "ok");
+        assert_eq!(found["count"], 1);
+        assert!(found["stats"].is_object());
+
+        let missing = serde_json::to_value(read_item(&mut db, "ns", "Yg", false, false)).unwrap();
+        assert_eq!(missing["status"], "value_not_found");
+        assert!(missing.get("stats").is_none());
+
+        let filtered = serde_json::to_string(&BulkReadItem::filtered_out("ns", "YQ")).unwrap();
+        assert_eq!(filtered, r#"{"namespace":"ns","value":"YQ","status":"filtered_out"}"#);
+        let denied = Error::Forbidden(String::from("Cannot read namespace: /ns"));
+        let denied = serde_json::to_value(BulkReadItem::failed("ns", "YQ", &denied)).unwrap();
+        assert_eq!(denied["status"], "forbidden");
+
+        let empty = BulkReadResponse { items: Vec::new() };
+        assert_eq!(serde_json::to_string(&empty).unwrap(), r#"{"items":[]}"#);
+    }
+}
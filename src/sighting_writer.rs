@@ -1,9 +1,250 @@
 // extern crate base64;
 // use base64::{decode_config, encode_config, URL_SAFE_NO_PAD};
 
+use actix_web::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use crate::attribute::NOW;
 use crate::db::Database;
+use crate::error::Error;
+
+#[derive(Serialize, Deserialize)]
+pub struct BulkSighting {
+    pub namespace: String,
+    pub value: String,
+    pub timestamp: Option<i64>,
+    pub noshadow: bool,
+}
+
+// How /wb deals with items that cannot be written
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteMode {
+    // The other items are written
+    #[default]
+    BestEffort,
+    // Nothing is written, and db_log has the whole batch in one record
+    #[serde(alias = "atomic")]
+    AllOrNothing,
+}
+
+// One item of a bulk write: status is "written" along with the count after the write,
+// "not_written" when another item failed in all_or_nothing mode, or the code of its error.
+#[derive(Serialize)]
+pub struct BulkWriteItem<'a> {
+    namespace: &'a str,
+    value: &'a str,
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    count: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+impl<'a> BulkWriteItem<'a> {
+    pub fn written(namespace: &'a str, value: &'a str, count: u128) -> BulkWriteItem<'a> {
+        BulkWriteItem {
+            namespace,
+            value,
+            status: "written",
+            count: Some(count),
+            message: None,
+        }
+    }
+
+    pub fn not_written(namespace: &'a str, value: &'a str) -> BulkWriteItem<'a> {
+        BulkWriteItem {
+            namespace,
+            value,
+            status: "not_written",
+            count: None,
+            message: None,
+        }
+    }
+
+    pub fn failed(namespace: &'a str, value: &'a str, e: &Error) -> BulkWriteItem<'a> {
+        BulkWriteItem {
+            namespace,
+            value,
+            status: e.code(),
+            count: None,
+            message: Some(e.to_string()),
+        }
+    }
+}
+
+// The code and message are those of the error that stopped an all_or_nothing write
+#[derive(Serialize)]
+pub struct BulkWriteResponse<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    pub written: usize,
+    pub items: Vec<BulkWriteItem<'a>>,
+}
+
+// What write refuses, checked before anything is written
+pub fn validate(path: &str, value: &str) -> Result<(), Error> {
+    if path.is_empty() {
+        return Err(Error::BadRequest(String::from("Cannot write to an empty namespace.")));
+    }
+    if value.is_empty() {
+        return Err(Error::BadRequest(String::from("Cannot write an empty value.")));
+    }
+    Ok(())
+}
+
+// Return the count of the written value
+pub fn write(db: &mut Database, path: &str, value: &str, timestamp: i64) -> Result<u128, Error> {
+    validate(path, value)?;
+    Ok(db.write(path, value, timestamp, true))
+}
+
+// Bulk write along with the status to answer. Every item is checked first, with `allowed` for
+// its namespace; `quota` is then given the number of writes and of new values of the whole
+// request. A quota error is the error of the request, whatever the mode.
+pub fn write_bulk<'a>(
+    db: &mut Database,
+    sightings: &'a [BulkSighting],
+    mode: WriteMode,
+    allowed: impl Fn(&str) -> Result<(), Error>,
+    quota: impl FnOnce(u64, u64) -> Result<(), Error>,
+) -> Result<(StatusCode, BulkWriteResponse<'a>), Error> {
+    let checked: Vec<Result<(), Error>> = sightings
+        .iter()
+        .map(|v| {
+            allowed(&v.namespace)?;
+            validate(&v.namespace, &v.value)
+        })
+        .collect();
+
+    if mode == WriteMode::AllOrNothing {
+        if let Some(failed) = checked.iter().find_map(|c| c.as_ref().err()) {
+            let items = sightings
+                .iter()
+                .zip(&checked)
+                .map(|(v, c)| match c {
+                    Ok(()) => BulkWriteItem::not_written(&v.namespace, &v.value),
+                    Err(e) => BulkWriteItem::failed(&v.namespace, &v.value, e),
+                })
+                .collect();
+            let response = BulkWriteResponse {
+                code: Some(failed.code()),
+                message: Some(format!("Nothing was written: {}", failed)),
+                written: 0,
+                items,
+            };
+            return Ok((failed.status(), response));
+        }
+    }
+
+    let mut new_values = HashSet::new();
+    let mut writes = 0;
+    for (v, _) in sightings.iter().zip(&checked).filter(|(_, c)| c.is_ok()) {
+        writes += 1;
+        if db.get_count(&v.namespace, &v.value) == 0 {
+            new_values.insert((v.namespace.as_str(), v.value.as_str()));
+        }
+    }
+    quota(writes, new_values.len() as u64)?;
+
+    // Nothing can fail from here, and the caller holds the lock until the last item is written
+    if mode == WriteMode::AllOrNothing {
+        db.begin_batch();
+    }
+    let mut written = 0;
+    let mut items = Vec::new();
+    for (v, c) in sightings.iter().zip(checked) {
+        let result = c.and_then(|()| write(db, &v.namespace, &v.value, v.timestamp.unwrap_or(NOW)));
+        items.push(match result {
+            Ok(count) => {
+                written += 1;
+                BulkWriteItem::written(&v.namespace, &v.value, count)
+            }
+            Err(e) => BulkWriteItem::failed(&v.namespace, &v.value, &e),
+        });
+    }
+    if mode == WriteMode::AllOrNothing {
+        db.commit_batch();
+    }
+
+    let response = BulkWriteResponse {
+        code: None,
+        message: None,
+        written,
+        items,
+    };
+    Ok((StatusCode::OK, response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(validate("ns", "YQ").is_ok());
+        assert_eq!(validate("ns", "").unwrap_err().code(), "bad_request");
+
+        let mode: WriteMode = serde_json::from_str(r#""all_or_nothing""#).unwrap();
+        assert_eq!(mode, WriteMode::AllOrNothing);
+        let mode: WriteMode = serde_json::from_str(r#""atomic""#).unwrap();
+        assert_eq!(mode, WriteMode::AllOrNothing);
+    }
+
+    fn sighting(namespace: &str, value: &str) -> BulkSighting {
+        BulkSighting {
+            namespace: namespace.to_string(),
+            value: value.to_string(),
+            timestamp: Some(1000),
+            noshadow: false,
+        }
+    }
+
+    fn feeds_only(namespace: &str) -> Result<(), Error> {
+        if namespace.starts_with("feeds/") {
+            Ok(())
+        } else {
+            Err(Error::Forbidden(format!("Cannot write to namespace: /{}", namespace)))
+        }
+    }
+
+    #[test]
+    fn test_write_bulk_modes() {
+        let sightings = vec![sighting("feeds/a", "YQ"), sighting("other", "YQ"), sighting("feeds/a", "")];
+
+        let mut db = Database::new();
+        let (status, response) =
+            write_bulk(&mut db, &sightings, WriteMode::BestEffort, feeds_only, |_, _| Ok(())).unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response.written, 1);
+        let statuses: Vec<&str> = response.items.iter().map(|item| item.status).collect();
+        assert_eq!(statuses, vec!["written", "forbidden", "bad_request"]);
+        assert_eq!(db.get_count("feeds/a", "YQ"), 1);
+
+        let mut db = Database::new();
+        let (status, response) =
+            write_bulk(&mut db, &sightings, WriteMode::AllOrNothing, feeds_only, |_, _| Ok(())).unwrap();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(response.code, Some("forbidden"));
+        assert_eq!(response.written, 0);
+        let statuses: Vec<&str> = response.items.iter().map(|item| item.status).collect();
+        assert_eq!(statuses, vec!["not_written", "forbidden", "bad_request"]);
+        assert_eq!(db.get_count("feeds/a", "YQ"), 0);
 
-pub fn write(db: &mut Database, path: &str, value: &str, timestamp: i64) -> bool {
-    db.write(path, value, timestamp, true);
-    true
+        // The quota sees the valid items of the whole request, the new values counted once
+        let sightings = vec![sighting("feeds/a", "YQ"), sighting("feeds/a", "YQ"), sighting("other", "Yg")];
+        let mut db = Database::new();
+        let exceeded = write_bulk(&mut db, &sightings, WriteMode::BestEffort, feeds_only, |writes, new_values| {
+            assert_eq!((writes, new_values), (2, 1));
+            Err(Error::TooManyRequests {
+                message: String::from("Daily write quota exceeded."),
+                retry_after: 60,
+            })
+        });
+        assert_eq!(exceeded.err().unwrap().code(), "too_many_requests");
+        assert_eq!(db.get_count("feeds/a", "YQ"), 0);
+    }
 }
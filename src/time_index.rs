@@ -0,0 +1,203 @@
+use qstring::QString;
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use crate::attribute::Attribute;
+use crate::error::Error;
+
+// Time window applied to namespace and bulk reads.
+// Lower bounds (*_after) are inclusive, upper bounds (*_before) are exclusive.
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+pub struct TimeFilter {
+    pub first_seen_after: Option<i64>,
+    pub first_seen_before: Option<i64>,
+    pub last_seen_after: Option<i64>,
+    pub last_seen_before: Option<i64>,
+}
+
+fn in_window(timestamp: i64, after: Option<i64>, before: Option<i64>) -> bool {
+    after.is_none_or(|a| timestamp >= a) && before.is_none_or(|b| timestamp < b)
+}
+
+impl TimeFilter {
+    pub fn from_query(query_string: &QString) -> Result<TimeFilter, Error> {
+        let get = |name: &str| match query_string.get(name) {
+            Some(v) => v
+                .parse::<i64>()
+                .map(Some)
+                .map_err(|_| Error::BadRequest(format!("{}= must be a unix timestamp.", name))),
+            None => Ok(None),
+        };
+        Ok(TimeFilter {
+            first_seen_after: get("first_seen_after")?,
+            first_seen_before: get("first_seen_before")?,
+            last_seen_after: get("last_seen_after")?,
+            last_seen_before: get("last_seen_before")?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.first_seen_after.is_none()
+            && self.first_seen_before.is_none()
+            && self.last_seen_after.is_none()
+            && self.last_seen_before.is_none()
+    }
+
+    pub fn matches(&self, attr: &Attribute) -> bool {
+        in_window(attr.first_seen.timestamp(), self.first_seen_after, self.first_seen_before)
+            && in_window(attr.last_seen.timestamp(), self.last_seen_after, self.last_seen_before)
+    }
+}
+
+// Position of a value in the last_seen ordering, to page through a namespace
+pub type Cursor = (i64, Arc<str>);
+
+// Secondary index of a namespace, ordered by first and last seen time,
+// so time window queries only walk the matching range. Both orderings share one copy of each value.
+#[derive(Default)]
+pub struct TimeIndex {
+    first_seen: BTreeSet<(i64, Arc<str>)>,
+    last_seen: BTreeSet<(i64, Arc<str>)>,
+}
+
+fn range<'a>(
+    index: &'a BTreeSet<(i64, Arc<str>)>,
+    after: Option<i64>,
+    before: Option<i64>,
+) -> Box<dyn Iterator<Item = &'a str> + 'a> {
+    if let (Some(a), Some(b)) = (after, before) {
+        if a >= b {
+            // BTreeSet::range panics on inverted bounds
+            return Box::new(std::iter::empty());
+        }
+    }
+    let lower = match after {
+        Some(a) => Bound::Included((a, Arc::from(""))),
+        None => Bound::Unbounded,
+    };
+    let upper = match before {
+        Some(b) => Bound::Excluded((b, Arc::from(""))),
+        None => Bound::Unbounded,
+    };
+    Box::new(index.range((lower, upper)).map(|(_, v)| &**v))
+}
+
+impl TimeIndex {
+    // Moves a value in the index; `previous` holds its (first_seen, last_seen) before the update.
+    pub fn update(&mut self, value: &str, previous: Option<(i64, i64)>, current: (i64, i64)) {
+        if previous == Some(current) {
+            return;
+        }
+        let mut value: Arc<str> = Arc::from(value);
+        if let Some((first_seen, last_seen)) = previous {
+            if let Some((_, indexed)) = self.first_seen.take(&(first_seen, value.clone())) {
+                value = indexed;
+            }
+            self.last_seen.remove(&(last_seen, value.clone()));
+        }
+        self.first_seen.insert((current.0, value.clone()));
+        self.last_seen.insert((current.1, value));
+    }
+
+    // Values by last_seen within the last_seen bounds of the filter, after the cursor when given
+    pub fn by_last_seen<'a>(
+        &'a self,
+        after: Option<&Cursor>,
+        filter: &TimeFilter,
+    ) -> Box<dyn Iterator<Item = &'a Cursor> + 'a> {
+        let lower = match (after, filter.last_seen_after) {
+            (Some(cursor), _) => Bound::Excluded(cursor.clone()),
+            (None, Some(a)) => Bound::Included((a, Arc::from(""))),
+            (None, None) => Bound::Unbounded,
+        };
+        let upper = match filter.last_seen_before {
+            Some(b) => Bound::Excluded((b, Arc::from(""))),
+            None => Bound::Unbounded,
+        };
+        // BTreeSet::range panics on inverted bounds
+        if let (Bound::Included(l) | Bound::Excluded(l), Bound::Excluded(u)) = (&lower, &upper) {
+            if l >= u {
+                return Box::new(std::iter::empty());
+            }
+        }
+        Box::new(self.last_seen.range((lower, upper)))
+    }
+
+    // Values whose indexed time falls in the filter window. The most selective looking
+    // bound drives the range walk, callers still need TimeFilter::matches for the others.
+    pub fn candidates<'a>(&'a self, filter: &TimeFilter) -> Box<dyn Iterator<Item = &'a str> + 'a> {
+        if filter.last_seen_after.is_some()
+            || (filter.first_seen_after.is_none() && filter.first_seen_before.is_none())
+        {
+            range(&self.last_seen, filter.last_seen_after, filter.last_seen_before)
+        } else {
+            range(&self.first_seen, filter.first_seen_after, filter.first_seen_before)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidates_follow_updates() {
+        let mut index = TimeIndex::default();
+        index.update("a", None, (10, 10));
+        index.update("b", None, (20, 20));
+        index.update("a", Some((10, 10)), (10, 30));
+
+        let filter = TimeFilter {
+            last_seen_after: Some(25),
+            ..TimeFilter::default()
+        };
+        assert_eq!(index.candidates(&filter).collect::<Vec<_>>(), vec!["a"]);
+
+        let filter = TimeFilter {
+            first_seen_before: Some(20),
+            ..TimeFilter::default()
+        };
+        assert_eq!(index.candidates(&filter).collect::<Vec<_>>(), vec!["a"]);
+
+        let filter = TimeFilter {
+            last_seen_after: Some(30),
+            last_seen_before: Some(10),
+            ..TimeFilter::default()
+        };
+        assert_eq!(index.candidates(&filter).count(), 0);
+    }
+
+    #[test]
+    fn test_from_query() {
+        let filter = TimeFilter::from_query(&QString::from("last_seen_after=20&other=x")).unwrap();
+        assert_eq!(filter.last_seen_after, Some(20));
+        assert!(filter.first_seen_after.is_none());
+        let malformed = TimeFilter::from_query(&QString::from("first_seen_before=yesterday"));
+        assert_eq!(malformed.err().unwrap().code(), "bad_request");
+    }
+
+    #[test]
+    fn test_pages_by_last_seen() {
+        let mut index = TimeIndex::default();
+        for (i, value) in ["a", "b", "c", "d"].iter().enumerate() {
+            index.update(value, None, (10, 10 * (i as i64 + 1)));
+        }
+        let filter = TimeFilter {
+            last_seen_after: Some(20),
+            ..TimeFilter::default()
+        };
+        let page: Vec<&Cursor> = index.by_last_seen(None, &filter).take(2).collect();
+        assert_eq!(page.iter().map(|(_, v)| &**v).collect::<Vec<_>>(), vec!["b", "c"]);
+        let next: Vec<&str> = index.by_last_seen(page.last().copied(), &filter).map(|(_, v)| &**v).collect();
+        assert_eq!(next, vec!["d"]);
+
+        let filter = TimeFilter {
+            last_seen_before: Some(30),
+            ..TimeFilter::default()
+        };
+        let last = (20, Arc::from("b"));
+        assert_eq!(index.by_last_seen(Some(&last), &filter).count(), 0);
+    }
+}
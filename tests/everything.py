@@ -1,11 +1,12 @@
 #!/usr/bin/env python3
 import sightingdb
 import sys
+import os
 import json
 
 print(sightingdb.__file__)
 
-con = sightingdb.connection(host="localhost", apikey="changeme")
+con = sightingdb.connection(host="localhost", apikey=os.environ.get("SIGHTINGDB_APIKEY", "changeme"))
 con.disable_ssl_warnings()
 
 deleter = sightingdb.delete(con)
@@ -47,7 +48,7 @@ def test_one_read(reader, namespace=None, value=None, first_seen=None, consensus
     else:
         print("\033[92mOK\033[0m")
 
-con = sightingdb.connection(host="localhost", apikey="changeme")
+con = sightingdb.connection(host="localhost", apikey=os.environ.get("SIGHTINGDB_APIKEY", "changeme"))
 reader = sightingdb.reader(con)
 
 test_one_read(reader, namespace="/namespace", value="127.0.0.1", first_seen=5555, consensus=2)